@@ -0,0 +1,42 @@
+// Build script
+// src/initramfs.rs embeds the archive the Makefile packs (initramfs.cpio), from OUT_DIR so that
+// cargo build and cargo clippy on their own work on a clean checkout too
+// without the archive the kernel gets an empty one, just the trailer, and the initramfs
+// self test at boot fails, so run make to get the real thing
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const ARCHIVE: &str = "initramfs.cpio";
+
+//a newc archive with nothing but the TRAILER!!! entry
+fn empty_archive() -> Vec<u8> {
+    let name: &[u8] = b"TRAILER!!!\0";
+    let mut archive: Vec<u8> = b"070701".to_vec();
+    //ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor, rdevminor,
+    //namesize and check
+    let fields: [usize; 13] = [0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, name.len(), 0];
+    for field in fields {
+        archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+    }
+    archive.extend_from_slice(name);
+    while archive.len() % 4 != 0 {
+        archive.push(0);
+    }
+    archive
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", ARCHIVE);
+    let out: PathBuf = PathBuf::from(env::var("OUT_DIR").unwrap()).join(ARCHIVE);
+    if Path::new(ARCHIVE).exists() {
+        fs::copy(ARCHIVE, &out).unwrap();
+    } else {
+        println!(
+            "cargo:warning={} not found, embedding an empty initramfs (run make to build it)",
+            ARCHIVE
+        );
+        fs::write(&out, empty_archive()).unwrap();
+    }
+}
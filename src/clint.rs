@@ -0,0 +1,242 @@
+// Core Local Interruptor (CLINT) timer
+// mtime is a 64 bit counter that ticks at a fixed rate and never goes backwards,
+// a machine timer interrupt fires when mtime >= mtimecmp for a hart
+// machine timer interrupts can't be delegated, so the handler in entry.S
+// turns them into supervisor software interrupts which end up in handle_timer_interrupt
+// under sbi firmware the clint belongs to the firmware, we read the time csr instead of mtime
+// and ask the firmware for supervisor timer interrupts, which also end up in handle_timer_interrupt
+
+use crate::mmu;
+use crate::sbi;
+use crate::trap;
+use crate::CLINT_ADDR;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+pub const CLINT_SIZE: usize = 0x10000;
+const MTIMECMP_OFFSET: usize = 0x4000;
+const MTIME_OFFSET: usize = 0xbff8;
+
+//timebase-frequency of the qemu virt board, used if the device tree doesn't say
+pub const DEFAULT_TICKS_PER_SECOND: u64 = 10_000_000;
+static TICKS_PER_SECOND: AtomicU64 = AtomicU64::new(DEFAULT_TICKS_PER_SECOND);
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+const MAX_TIMERS: usize = 16;
+
+//sie.SSIE, the forwarded timer interrupt
+const SIE_SSIE: usize = 1 << 1;
+//sie.STIE, the timer interrupt sbi firmware raises
+const SIE_STIE: usize = 1 << 5;
+
+fn read_mtime() -> u64 {
+    if sbi::has_timer() {
+        let time: u64;
+        unsafe { core::arch::asm!("rdtime {}", out(reg) time) }
+        return time;
+    }
+    let mtime_ptr: *const u64 = mmu::phys_to_virt(unsafe { CLINT_ADDR } + MTIME_OFFSET) as *const u64;
+    unsafe { mtime_ptr.read_volatile() }
+}
+
+fn write_mtimecmp(hart: usize, value: u64) {
+    let mtimecmp_ptr: *mut u64 =
+        mmu::phys_to_virt(unsafe { CLINT_ADDR } + MTIMECMP_OFFSET + 8 * hart) as *mut u64;
+    unsafe { mtimecmp_ptr.write_volatile(value) }
+}
+
+fn ticks_per_second() -> u128 {
+    TICKS_PER_SECOND.load(Ordering::Relaxed) as u128
+}
+
+fn duration_to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * ticks_per_second() / NANOS_PER_SECOND) as u64
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos((ticks as u128 * NANOS_PER_SECOND / ticks_per_second()) as u64)
+}
+
+//a point on the monotonic clock
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    ticks: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant {
+            ticks: read_mtime(),
+        }
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.ticks.saturating_sub(earlier.ticks))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    //time since the clock started counting (at reset)
+    pub fn since_boot(&self) -> Duration {
+        ticks_to_duration(self.ticks)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        Some(Instant {
+            ticks: self.ticks.checked_add(duration_to_ticks(duration))?,
+        })
+    }
+}
+
+impl core::ops::Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).unwrap()
+    }
+}
+
+impl core::ops::Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId(usize);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    deadline: Instant,
+    period: Option<Duration>,
+    //runs in interrupt context with interrupts off, keep it short
+    callback: fn(),
+}
+
+static TIMERS: spin::Mutex<[Option<Timer>; MAX_TIMERS]> = spin::Mutex::new([None; MAX_TIMERS]);
+
+//point mtimecmp at the earliest pending deadline, or forever if there are none
+fn program_next_deadline(timers: &[Option<Timer>; MAX_TIMERS]) {
+    let next: u64 = timers
+        .iter()
+        .flatten()
+        .map(|timer| timer.deadline.ticks)
+        .min()
+        .unwrap_or(u64::MAX);
+    set_deadline(next);
+}
+
+//only the boot hart runs the kernel for now
+fn set_deadline(ticks: u64) {
+    if sbi::has_timer() {
+        sbi::set_timer(ticks).unwrap();
+    } else {
+        write_mtimecmp(crate::boot_hart(), ticks);
+    }
+}
+
+fn add_timer(timer: Timer) -> Result<TimerId, &'static str> {
+    trap::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let slot: usize = timers
+            .iter()
+            .position(|timer| timer.is_none())
+            .ok_or("no free timer slots")?;
+        timers[slot] = Some(timer);
+        program_next_deadline(&timers);
+        Ok(TimerId(slot))
+    })
+}
+
+//run callback once after the given delay
+pub fn set_oneshot(after: Duration, callback: fn()) -> Result<TimerId, &'static str> {
+    add_timer(Timer {
+        deadline: Instant::now() + after,
+        period: None,
+        callback,
+    })
+}
+
+//run callback every period until cancelled
+pub fn set_periodic(period: Duration, callback: fn()) -> Result<TimerId, &'static str> {
+    if duration_to_ticks(period) == 0 {
+        return Err("timer period shorter than one tick");
+    }
+    add_timer(Timer {
+        deadline: Instant::now() + period,
+        period: Some(period),
+        callback,
+    })
+}
+
+pub fn cancel(id: TimerId) {
+    trap::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        timers[id.0] = None;
+        program_next_deadline(&timers);
+    });
+}
+
+//called from the trap handler
+pub fn handle_timer_interrupt() {
+    let now: Instant = Instant::now();
+    let mut expired: [Option<fn()>; MAX_TIMERS] = [None; MAX_TIMERS];
+
+    {
+        let mut timers = TIMERS.lock();
+        for (slot, entry) in timers.iter_mut().enumerate() {
+            let Some(timer) = entry else {
+                continue;
+            };
+            if timer.deadline > now {
+                continue;
+            }
+            expired[slot] = Some(timer.callback);
+            match timer.period {
+                //skip any periods we missed instead of firing repeatedly to catch up
+                Some(period) => {
+                    while timer.deadline <= now {
+                        timer.deadline = timer.deadline + period;
+                    }
+                }
+                None => *entry = None,
+            }
+        }
+        program_next_deadline(&timers);
+    }
+
+    //callbacks run without the lock so they can set up new timers
+    for callback in expired.iter().flatten() {
+        callback();
+    }
+}
+
+fn wake_up() {}
+
+//idle the hart until at least duration has passed
+pub fn sleep(duration: Duration) {
+    let deadline: Instant = Instant::now() + duration;
+    //out of timers nothing is sure to wake a wfi, so poll the time instead
+    let armed: bool = set_oneshot(duration, wake_up).is_ok();
+    while Instant::now() < deadline {
+        if armed {
+            unsafe { core::arch::asm!("wfi") }
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+pub fn init(timebase_frequency: Option<u64>) {
+    if let Some(frequency) = timebase_frequency {
+        assert!(frequency != 0);
+        TICKS_PER_SECOND.store(frequency, Ordering::Relaxed);
+    }
+    set_deadline(u64::MAX);
+    unsafe { core::arch::asm!("csrs sie, {}", in(reg) SIE_SSIE | SIE_STIE) }
+    trap::enable_interrupts();
+}
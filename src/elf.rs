@@ -0,0 +1,331 @@
+// ELF64 loader for RISC-V executables
+// see the System V gABI (the ELF chapters) and the RISC-V psABI for the machine specific parts
+// only statically linked executables (ET_EXEC) for now, nothing gets relocated, every PT_LOAD
+// segment goes where p_vaddr says in the lower (user) half of the address space
+// every page a segment touches gets a fresh zeroed frame (AddressSpace::map_new), the file
+// contents are copied in and the zeroes that are left past p_filesz are the bss
+// once loaded each segment is mapped with the permissions from its p_flags, and user mode
+// the image is checked all the way through before anything is mapped, a bad one is an ElfError
+// and never a panic
+
+use crate::memory_alloc::PAGE_SIZE;
+use crate::mmu::address_space::AddressSpace;
+use crate::mmu::page_table::{self, PteBits};
+use crate::mmu::paging;
+use crate::process::{USER_HEAP_SIZE, USER_HEAP_START, USER_STACK_SIZE, USER_STACK_TOP};
+use alloc::vec::Vec;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+
+//e_type
+const TYPE_EXEC: u16 = 2;
+//e_machine
+const MACHINE_RISCV: u16 = 243;
+
+//p_type
+const PT_LOAD: u32 = 1;
+//p_flags
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    //smaller than the header, or than where the header says the program headers are
+    TooShort,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    BadVersion,
+    //not ET_EXEC, shared objects and position independent executables can't be loaded yet
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeaders,
+    NoSegments,
+    //p_offset + p_filesz runs past the end of the image
+    SegmentOutOfFile,
+    //p_filesz is bigger than p_memsz
+    SegmentTooSmall,
+    //not in the user half of the address space, or where processes keep their stack or heap
+    BadAddress,
+    //two segments want the same page, or one wants a page that is already mapped
+    Overlap,
+    //a segment with no permissions at all
+    BadFlags,
+    //the entry point isn't in an executable segment
+    BadEntry,
+    //mapping the segments failed, out of memory most likely
+    Map(&'static str),
+}
+
+impl ElfError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ElfError::TooShort => "elf image too short",
+            ElfError::BadMagic => "not an elf image",
+            ElfError::NotElf64 => "not a 64 bit elf image",
+            ElfError::NotLittleEndian => "elf image not little endian",
+            ElfError::BadVersion => "unknown elf version",
+            ElfError::NotExecutable => "elf image not an executable",
+            ElfError::WrongMachine => "elf image not for risc-v",
+            ElfError::BadProgramHeaders => "bad elf program headers",
+            ElfError::NoSegments => "elf image has nothing to load",
+            ElfError::SegmentOutOfFile => "elf segment past the end of the image",
+            ElfError::SegmentTooSmall => "elf segment file size bigger than memory size",
+            ElfError::BadAddress => "elf segment outside of user memory",
+            ElfError::Overlap => "elf segments overlap",
+            ElfError::BadFlags => "elf segment with no permissions",
+            ElfError::BadEntry => "elf entry point not in an executable segment",
+            ElfError::Map(error) => error,
+        }
+    }
+}
+
+//the parts of the file header the loader cares about
+struct Header {
+    kind: u16,
+    machine: u16,
+    entry: usize,
+    program_headers: usize,
+    program_header_size: usize,
+    program_header_count: usize,
+}
+
+//a PT_LOAD program header
+#[derive(Clone, Copy)]
+struct Segment {
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    file_size: usize,
+    mem_size: usize,
+}
+
+impl Segment {
+    //the pages the segment touches
+    fn pages(&self) -> (usize, usize) {
+        let start: usize = self.vaddr / PAGE_SIZE * PAGE_SIZE;
+        let end: usize = (self.vaddr + self.mem_size).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        (start, end)
+    }
+
+    fn protection_bits(&self) -> usize {
+        let mut bits: usize = PteBits::UserMode.val();
+        //write only isn't a valid pte, writable segments are readable too
+        if self.flags & (PF_R | PF_W) != 0 {
+            bits |= PteBits::Read.val();
+        }
+        if self.flags & PF_W != 0 {
+            bits |= PteBits::Write.val();
+        }
+        if self.flags & PF_X != 0 {
+            bits |= PteBits::Execute.val();
+        }
+        bits
+    }
+}
+
+fn read_u16(image: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(image[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(image: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(image: &[u8], offset: usize) -> usize {
+    u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap()) as usize
+}
+
+fn parse_header(image: &[u8]) -> Result<Header, ElfError> {
+    if image.len() < HEADER_SIZE {
+        return Err(ElfError::TooShort);
+    }
+    if image[0..4] != MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    if image[4] != CLASS_64 {
+        return Err(ElfError::NotElf64);
+    }
+    if image[5] != DATA_LITTLE_ENDIAN {
+        return Err(ElfError::NotLittleEndian);
+    }
+    if image[6] != VERSION_CURRENT || read_u32(image, 20) != VERSION_CURRENT as u32 {
+        return Err(ElfError::BadVersion);
+    }
+    Ok(Header {
+        kind: read_u16(image, 16),
+        machine: read_u16(image, 18),
+        entry: read_u64(image, 24),
+        program_headers: read_u64(image, 32),
+        program_header_size: read_u16(image, 54) as usize,
+        program_header_count: read_u16(image, 56) as usize,
+    })
+}
+
+//the PT_LOAD segments, checked against the image and each other
+fn parse_segments(image: &[u8], header: &Header) -> Result<Vec<Segment>, ElfError> {
+    if header.program_header_size != PROGRAM_HEADER_SIZE {
+        return Err(ElfError::BadProgramHeaders);
+    }
+    let table_end: usize = header
+        .program_header_count
+        .checked_mul(PROGRAM_HEADER_SIZE)
+        .and_then(|size| size.checked_add(header.program_headers))
+        .ok_or(ElfError::BadProgramHeaders)?;
+    if table_end > image.len() {
+        return Err(ElfError::TooShort);
+    }
+
+    let mut segments: Vec<Segment> = Vec::new();
+    for i in 0..header.program_header_count {
+        let at: usize = header.program_headers + i * PROGRAM_HEADER_SIZE;
+        if read_u32(image, at) != PT_LOAD {
+            continue;
+        }
+        let segment: Segment = Segment {
+            flags: read_u32(image, at + 4),
+            offset: read_u64(image, at + 8),
+            vaddr: read_u64(image, at + 16),
+            file_size: read_u64(image, at + 32),
+            mem_size: read_u64(image, at + 40),
+        };
+        if segment.mem_size == 0 {
+            continue;
+        }
+        if segment.file_size > segment.mem_size {
+            return Err(ElfError::SegmentTooSmall);
+        }
+        match segment.offset.checked_add(segment.file_size) {
+            Some(end) if end <= image.len() => {}
+            _ => return Err(ElfError::SegmentOutOfFile),
+        }
+        //if the last byte is in the user half all of the segment is, page 0 stays unmapped
+        let last: usize = segment
+            .vaddr
+            .checked_add(segment.mem_size - 1)
+            .ok_or(ElfError::BadAddress)?;
+        if segment.vaddr < PAGE_SIZE
+            || page_table::is_upper_half(last)
+            || !paging::active().is_canonical(last)
+        {
+            return Err(ElfError::BadAddress);
+        }
+        //Process::from_elf reserves these right after loading
+        let reserved: [(usize, usize); 2] = [
+            (USER_HEAP_START, USER_HEAP_START + USER_HEAP_SIZE),
+            (USER_STACK_TOP - USER_STACK_SIZE, USER_STACK_TOP),
+        ];
+        if reserved
+            .iter()
+            .any(|&(start, end)| segment.vaddr < end && last >= start)
+        {
+            return Err(ElfError::BadAddress);
+        }
+        if segment.flags & (PF_R | PF_W | PF_X) == 0 {
+            return Err(ElfError::BadFlags);
+        }
+        segments.push(segment);
+    }
+    if segments.is_empty() {
+        return Err(ElfError::NoSegments);
+    }
+
+    segments.sort_by_key(|segment| segment.vaddr);
+    if segments
+        .windows(2)
+        .any(|pair| pair[0].pages().1 > pair[1].pages().0)
+    {
+        return Err(ElfError::Overlap);
+    }
+    Ok(segments)
+}
+
+//the entry point and the segments to load
+fn parse(image: &[u8]) -> Result<(usize, Vec<Segment>), ElfError> {
+    let header: Header = parse_header(image)?;
+    if header.kind != TYPE_EXEC {
+        return Err(ElfError::NotExecutable);
+    }
+    if header.machine != MACHINE_RISCV {
+        return Err(ElfError::WrongMachine);
+    }
+    let segments: Vec<Segment> = parse_segments(image, &header)?;
+    let executable: bool = segments.iter().any(|segment| {
+        segment.flags & PF_X != 0
+            && (segment.vaddr..segment.vaddr + segment.mem_size).contains(&header.entry)
+    });
+    if !executable {
+        return Err(ElfError::BadEntry);
+    }
+    Ok((header.entry, segments))
+}
+
+//check image without loading it, returns the entry point
+pub fn validate(image: &[u8]) -> Result<usize, ElfError> {
+    parse(image).map(|(entry, _)| entry)
+}
+
+//load the segments of image into space, returns the entry point
+//on an error nothing it mapped stays mapped
+pub fn load(space: &mut AddressSpace, image: &[u8]) -> Result<usize, ElfError> {
+    let (entry, segments): (usize, Vec<Segment>) = parse(image)?;
+    for segment in segments.iter() {
+        let (start, end): (usize, usize) = segment.pages();
+        if (start..end)
+            .step_by(PAGE_SIZE)
+            .any(|page| space.translate(page).is_ok())
+        {
+            return Err(ElfError::Overlap);
+        }
+    }
+
+    let mut loaded: Vec<(usize, usize)> = Vec::new();
+    for segment in segments.iter() {
+        let (start, end): (usize, usize) = segment.pages();
+        if let Err(error) = load_segment(space, image, segment) {
+            //map_new may have gotten part of the way
+            loaded.push((start, end));
+            for (start, end) in loaded {
+                space
+                    .unmap(start, end - start)
+                    .expect("failed to unmap a partly loaded elf image");
+            }
+            return Err(error);
+        }
+        loaded.push((start, end));
+    }
+    Ok(entry)
+}
+
+fn load_segment(space: &mut AddressSpace, image: &[u8], segment: &Segment) -> Result<(), ElfError> {
+    let (start, end): (usize, usize) = segment.pages();
+    let len: usize = end - start;
+    //the frames come zeroed, which takes care of the bss
+    space
+        .map_new(
+            start,
+            len,
+            PteBits::Read.val() | PteBits::Write.val() | PteBits::UserMode.val(),
+        )
+        .map_err(ElfError::Map)?;
+    space
+        .copy_to(
+            segment.vaddr,
+            &image[segment.offset..segment.offset + segment.file_size],
+        )
+        .map_err(ElfError::Map)?;
+    space
+        .protect(
+            start,
+            len,
+            page_table::accessed_dirty(segment.protection_bits()),
+        )
+        .map_err(ElfError::Map)
+}
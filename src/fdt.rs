@@ -0,0 +1,398 @@
+// Flattened device tree (FDT / DTB) parser
+// qemu (and opensbi) hand us a pointer to one of these in a1 at boot,
+// it describes how much ram there is and where the devices live
+// see the devicetree specification, chapter 5 "Flattened Devicetree (DTB) Format"
+// everything in it is big endian
+
+use crate::mmu;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+const HEADER_SIZE: usize = 40;
+const MAX_DEPTH: usize = 16;
+pub const MAX_RESERVED: usize = 8;
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let word: [u8; 4] = bytes.get(offset..offset + 4)?.try_into().ok()?;
+    Some(u32::from_be_bytes(word))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let word: [u8; 8] = bytes.get(offset..offset + 8)?.try_into().ok()?;
+    Some(u64::from_be_bytes(word))
+}
+
+//a number made of `cells` 32 bit cells, as used by reg
+fn read_cells(bytes: &[u8], offset: usize, cells: usize) -> Option<u64> {
+    match cells {
+        0 => Some(0),
+        1 => read_u32(bytes, offset).map(|value| value as u64),
+        2 => read_u64(bytes, offset),
+        _ => None,
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+//nul terminated string starting at offset
+fn read_str(bytes: &[u8], offset: usize) -> Option<&str> {
+    let rest: &[u8] = bytes.get(offset..)?;
+    let len: usize = rest.iter().position(|byte| *byte == 0)?;
+    core::str::from_utf8(&rest[..len]).ok()
+}
+
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+    mem_rsvmap_offset: usize,
+}
+
+impl<'a> Fdt<'a> {
+    pub fn from_bytes(data: &'a [u8]) -> Result<Fdt<'a>, &'static str> {
+        if data.len() < HEADER_SIZE {
+            return Err("fdt smaller than its header");
+        }
+        if read_u32(data, 0) != Some(FDT_MAGIC) {
+            return Err("bad fdt magic");
+        }
+        let header = |index: usize| read_u32(data, 4 * index).unwrap() as usize;
+        let total_size: usize = header(1);
+        let struct_offset: usize = header(2);
+        let strings_offset: usize = header(3);
+        let mem_rsvmap_offset: usize = header(4);
+        let last_comp_version: usize = header(6);
+        let strings_size: usize = header(8);
+        let struct_size: usize = header(9);
+
+        if last_comp_version > 17 {
+            return Err("unsupported fdt version");
+        }
+        if total_size > data.len() {
+            return Err("fdt truncated");
+        }
+        let structs: &[u8] = struct_offset
+            .checked_add(struct_size)
+            .and_then(|struct_end| data.get(struct_offset..struct_end))
+            .ok_or("fdt struct block out of bounds")?;
+        let strings: &[u8] = strings_offset
+            .checked_add(strings_size)
+            .and_then(|strings_end| data.get(strings_offset..strings_end))
+            .ok_or("fdt strings block out of bounds")?;
+
+        Ok(Fdt {
+            data: &data[..total_size],
+            structs,
+            strings,
+            mem_rsvmap_offset,
+        })
+    }
+
+    //the dtb lives in memory we never allocate from, so it can be 'static
+    //addr is the physical address the firmware gave us, it is read through the direct map
+    pub unsafe fn from_addr(addr: usize) -> Result<Fdt<'static>, &'static str> {
+        if addr == 0 || addr % 8 != 0 {
+            return Err("bad fdt address");
+        }
+        let addr: usize = mmu::phys_to_virt(addr);
+        let header: &[u8] = core::slice::from_raw_parts(addr as *const u8, HEADER_SIZE);
+        if read_u32(header, 0) != Some(FDT_MAGIC) {
+            return Err("bad fdt magic");
+        }
+        let total_size: usize = read_u32(header, 4).unwrap() as usize;
+        Fdt::from_bytes(core::slice::from_raw_parts(addr as *const u8, total_size))
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    //(address, size) pairs from the memory reservation block
+    pub fn reserved_ranges(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let data: &'a [u8] = self.data;
+        let start: usize = self.mem_rsvmap_offset;
+        (0..)
+            .map(move |index: usize| {
+                let offset: usize = start + 16 * index;
+                Some((read_u64(data, offset)?, read_u64(data, offset + 8)?))
+            })
+            .map_while(|entry| entry)
+            .take_while(|(address, size)| *address != 0 || *size != 0)
+    }
+
+    //call visitor on every node in the tree, parents before children
+    pub fn walk(&self, mut visitor: impl FnMut(&Node<'a>)) -> Result<(), &'static str> {
+        //#address-cells and #size-cells each node gives to its children, defaults from the spec
+        let mut cells: [(usize, usize); MAX_DEPTH] = [(2, 1); MAX_DEPTH];
+        let mut names: [&'a str; MAX_DEPTH] = [""; MAX_DEPTH];
+        let mut depth: usize = 0;
+        let mut offset: usize = 0;
+
+        loop {
+            let token: u32 = read_u32(self.structs, offset).ok_or("fdt struct block ended early")?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    if depth >= MAX_DEPTH {
+                        return Err("fdt nested too deep");
+                    }
+                    let name: &'a str =
+                        read_str(self.structs, offset).ok_or("bad fdt node name")?;
+                    offset = align4(offset + name.len() + 1);
+
+                    let parent_cells: (usize, usize) = if depth == 0 {
+                        (2, 1)
+                    } else {
+                        cells[depth - 1]
+                    };
+                    let node: Node<'a> = Node {
+                        name,
+                        parent: if depth == 0 { "" } else { names[depth - 1] },
+                        depth,
+                        props_offset: offset,
+                        address_cells: parent_cells.0,
+                        size_cells: parent_cells.1,
+                        fdt_structs: self.structs,
+                        fdt_strings: self.strings,
+                    };
+
+                    cells[depth] = (
+                        node.prop_u32("#address-cells")
+                            .map_or(2, |value| value as usize),
+                        node.prop_u32("#size-cells").map_or(1, |value| value as usize),
+                    );
+                    names[depth] = name;
+                    depth += 1;
+
+                    visitor(&node);
+                }
+                FDT_END_NODE => {
+                    if depth == 0 {
+                        return Err("unbalanced fdt end node");
+                    }
+                    depth -= 1;
+                }
+                FDT_PROP => {
+                    let len: usize = read_u32(self.structs, offset).ok_or("bad fdt prop")? as usize;
+                    offset = align4(offset + 8 + len);
+                }
+                FDT_NOP => {}
+                FDT_END => {
+                    return if depth == 0 {
+                        Ok(())
+                    } else {
+                        Err("fdt ended inside a node")
+                    };
+                }
+                _ => return Err("unknown fdt token"),
+            }
+        }
+    }
+}
+
+pub struct Node<'a> {
+    pub name: &'a str,
+    pub parent: &'a str,
+    //root is 0, /soc is 1, devices in /soc are 2
+    pub depth: usize,
+    props_offset: usize,
+    //cells used by this node's reg, these come from the parent
+    address_cells: usize,
+    size_cells: usize,
+    fdt_structs: &'a [u8],
+    fdt_strings: &'a [u8],
+}
+
+pub struct Prop<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+pub struct PropIter<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for PropIter<'a> {
+    type Item = Prop<'a>;
+
+    fn next(&mut self) -> Option<Prop<'a>> {
+        loop {
+            let token: u32 = read_u32(self.structs, self.offset)?;
+            match token {
+                FDT_NOP => self.offset += 4,
+                FDT_PROP => {
+                    let len: usize = read_u32(self.structs, self.offset + 4)? as usize;
+                    let name_offset: usize = read_u32(self.structs, self.offset + 8)? as usize;
+                    let value_start: usize = self.offset + 12;
+                    let value: &'a [u8] = self.structs.get(value_start..value_start + len)?;
+                    let name: &'a str = read_str(self.strings, name_offset)?;
+                    self.offset = align4(value_start + len);
+                    return Some(Prop { name, value });
+                }
+                //props always come before child nodes, anything else means we are done
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl<'a> Node<'a> {
+    pub fn props(&self) -> PropIter<'a> {
+        PropIter {
+            structs: self.fdt_structs,
+            strings: self.fdt_strings,
+            offset: self.props_offset,
+        }
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&'a [u8]> {
+        self.props()
+            .find(|prop| prop.name == name)
+            .map(|prop| prop.value)
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        read_u32(self.prop(name)?, 0)
+    }
+
+    //string property without its nul terminator
+    pub fn prop_str(&self, name: &str) -> Option<&'a str> {
+        read_str(self.prop(name)?, 0)
+    }
+
+    //name without the @unit-address
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap()
+    }
+
+    //compatible is a list of nul separated strings, most specific first
+    pub fn is_compatible(&self, wanted: &str) -> bool {
+        match self.prop("compatible") {
+            Some(list) => list
+                .split(|byte| *byte == 0)
+                .any(|entry| entry == wanted.as_bytes()),
+            None => false,
+        }
+    }
+
+    //first (address, size) pair of reg
+    pub fn reg(&self) -> Option<(usize, usize)> {
+        let reg: &[u8] = self.prop("reg")?;
+        let address: u64 = read_cells(reg, 0, self.address_cells)?;
+        let size: u64 = read_cells(reg, 4 * self.address_cells, self.size_cells)?;
+        Some((address as usize, size as usize))
+    }
+
+    //first entry of interrupts, only single cell interrupt specifiers (like the plic uses)
+    pub fn interrupt(&self) -> Option<u32> {
+        self.prop_u32("interrupts")
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Device {
+    pub base: usize,
+    pub size: usize,
+    pub irq: Option<u32>,
+}
+
+impl Device {
+    fn from_node(node: &Node) -> Option<Device> {
+        let (base, size) = node.reg()?;
+        Some(Device {
+            base,
+            size,
+            irq: node.interrupt(),
+        })
+    }
+}
+
+//everything the kernel wants to know about the machine it booted on
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BoardInfo {
+    pub memory: Option<(usize, usize)>,
+    pub bootargs: Option<&'static str>,
+    pub stdout_path: Option<&'static str>,
+    pub timebase_frequency: Option<u64>,
+    pub uart: Option<Device>,
+    pub syscon: Option<Device>,
+    pub clint: Option<Device>,
+    pub plic: Option<Device>,
+    //where the dtb itself lives, so the allocator can stay away from it
+    pub dtb: Option<(usize, usize)>,
+    //(address, size) pairs from the memory reservation block and the /reserved-memory node,
+    //firmware lives in these
+    pub reserved: [Option<(usize, usize)>; MAX_RESERVED],
+}
+
+impl BoardInfo {
+    pub fn from_fdt(fdt: &Fdt<'static>) -> Result<BoardInfo, &'static str> {
+        let mut info: BoardInfo = BoardInfo {
+            dtb: Some((mmu::virt_to_phys(fdt.data.as_ptr() as usize), fdt.total_size())),
+            ..Default::default()
+        };
+
+        for (slot, (address, size)) in fdt.reserved_ranges().take(MAX_RESERVED).enumerate() {
+            info.reserved[slot] = Some((address as usize, size as usize));
+        }
+
+        fdt.walk(|node| {
+            if node.depth == 1 {
+                match node.base_name() {
+                    "memory" if info.memory.is_none() => info.memory = node.reg(),
+                    "chosen" => {
+                        info.bootargs = node.prop_str("bootargs");
+                        info.stdout_path = node.prop_str("stdout-path");
+                    }
+                    "cpus" => {
+                        info.timebase_frequency =
+                            node.prop_u32("timebase-frequency").map(|value| value as u64)
+                    }
+                    _ => {}
+                }
+            }
+
+            //children of /reserved-memory without a reg only ask for memory to be set aside
+            //somewhere, the allocator hands out all of it so there is nothing to do for those
+            if node.parent == "reserved-memory" {
+                if let Some(slot) = info.reserved.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = node.reg();
+                }
+                return;
+            }
+
+            if node.parent != "soc" {
+                return;
+            }
+            if node.is_compatible("ns16550a") && info.uart.is_none() {
+                info.uart = Device::from_node(node);
+            } else if node.is_compatible("sifive,test0") || node.is_compatible("syscon") {
+                info.syscon = Device::from_node(node);
+            } else if node.is_compatible("riscv,clint0") || node.is_compatible("sifive,clint0") {
+                info.clint = Device::from_node(node);
+            } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0")
+            {
+                info.plic = Device::from_node(node);
+            }
+        })?;
+
+        if info.memory.is_none() {
+            return Err("fdt has no /memory node");
+        }
+        Ok(info)
+    }
+
+    pub unsafe fn from_addr(addr: usize) -> Result<BoardInfo, &'static str> {
+        let fdt: Fdt<'static> = Fdt::from_addr(addr)?;
+        BoardInfo::from_fdt(&fdt)
+    }
+}
@@ -0,0 +1,214 @@
+// Initramfs
+// files that ship inside the kernel image, the Makefile packs initramfs/ (and the user programs,
+// as bin/<name>) into a cpio archive in the "newc" format and include_bytes! embeds it here
+// (build.rs hands it over through OUT_DIR, an empty archive if make hasn't built it)
+// newc is what linux uses for its initramfs too, see the kernel's
+// Documentation/driver-api/early-userspace/buffer-format.rst, every entry is
+//   a 110 byte ascii header: "070701" and 13 fields of 8 hex digits
+//   ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor, rdevminor,
+//   namesize (with the nul) and check (always 0 for 070701)
+//   the path, nul terminated and padded so the data starts 4 byte aligned
+//   the data, padded to 4 bytes too
+// and the archive ends with an entry named TRAILER!!!
+// nothing gets copied, entries borrow their path and data from the archive
+
+static INITRAMFS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+const HEADER_SIZE: usize = 110;
+const MAGIC: &[u8] = b"070701";
+//070702 is the same with a checksum in check, which we don't verify
+const MAGIC_CRC: &[u8] = b"070702";
+const TRAILER: &str = "TRAILER!!!";
+
+//mode bits
+const MODE_TYPE: u32 = 0o170000;
+const MODE_FILE: u32 = 0o100000;
+const MODE_DIR: u32 = 0o040000;
+const MODE_SYMLINK: u32 = 0o120000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    //seconds since the unix epoch
+    pub mtime: u32,
+    pub size: usize,
+}
+
+impl Metadata {
+    pub fn is_file(&self) -> bool {
+        self.mode & MODE_TYPE == MODE_FILE
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & MODE_TYPE == MODE_DIR
+    }
+
+    //the data of a symlink is where it points
+    pub fn is_symlink(&self) -> bool {
+        self.mode & MODE_TYPE == MODE_SYMLINK
+    }
+
+    //the rwx bits
+    pub fn permissions(&self) -> u32 {
+        self.mode & 0o7777
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Entry<'a> {
+    //as stored, without the ./ or / in front
+    pub path: &'a str,
+    pub metadata: Metadata,
+    pub data: &'a [u8],
+}
+
+#[derive(Clone, Copy)]
+pub struct Archive<'a> {
+    bytes: &'a [u8],
+}
+
+//the archive built into the kernel
+pub fn archive() -> Archive<'static> {
+    Archive::new(INITRAMFS)
+}
+
+impl<'a> Archive<'a> {
+    pub fn new(bytes: &'a [u8]) -> Archive<'a> {
+        Archive { bytes }
+    }
+
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    //every entry up to the trailer, a broken one ends the walk with an error
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            bytes: self.bytes,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    //the entry at path, which may start with / or ./ like the stored paths may
+    pub fn find(&self, path: &str) -> Result<Entry<'a>, &'static str> {
+        let path: &str = normalize(path);
+        for entry in self.entries() {
+            let entry: Entry<'a> = entry?;
+            if entry.path == path {
+                return Ok(entry);
+            }
+        }
+        Err("no such file in initramfs")
+    }
+
+    pub fn metadata(&self, path: &str) -> Result<Metadata, &'static str> {
+        self.find(path).map(|entry| entry.metadata)
+    }
+
+    //contents of the regular file at path
+    pub fn read(&self, path: &str) -> Result<&'a [u8], &'static str> {
+        let entry: Entry<'a> = self.find(path)?;
+        if !entry.metadata.is_file() {
+            return Err("not a regular file");
+        }
+        Ok(entry.data)
+    }
+}
+
+pub struct Entries<'a> {
+    bytes: &'a [u8],
+    //where the next header starts
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, &'static str>;
+
+    fn next(&mut self) -> Option<Result<Entry<'a>, &'static str>> {
+        if self.done {
+            return None;
+        }
+        match parse_entry(self.bytes, self.offset) {
+            Ok(Some((entry, next))) => {
+                self.offset = next;
+                Some(Ok(entry))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(error) => {
+                self.done = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+fn normalize(path: &str) -> &str {
+    let path: &str = path.strip_prefix("./").unwrap_or(path);
+    path.trim_start_matches('/')
+}
+
+fn align4(offset: usize) -> usize {
+    offset.div_ceil(4) * 4
+}
+
+//one of the 8 hex digit header fields
+fn hex_field(header: &[u8], index: usize) -> Result<u32, &'static str> {
+    let start: usize = MAGIC.len() + index * 8;
+    let digits: &str =
+        core::str::from_utf8(&header[start..start + 8]).map_err(|_| "bad cpio header")?;
+    u32::from_str_radix(digits, 16).map_err(|_| "bad cpio header")
+}
+
+//the entry whose header is at offset and where the next one starts, None for the trailer
+fn parse_entry(bytes: &[u8], offset: usize) -> Result<Option<(Entry<'_>, usize)>, &'static str> {
+    let header: &[u8] = bytes
+        .get(offset..offset + HEADER_SIZE)
+        .ok_or("cpio archive truncated")?;
+    if &header[..MAGIC.len()] != MAGIC && &header[..MAGIC.len()] != MAGIC_CRC {
+        return Err("not a cpio newc archive");
+    }
+    let metadata: Metadata = Metadata {
+        ino: hex_field(header, 0)?,
+        mode: hex_field(header, 1)?,
+        uid: hex_field(header, 2)?,
+        gid: hex_field(header, 3)?,
+        nlink: hex_field(header, 4)?,
+        mtime: hex_field(header, 5)?,
+        size: hex_field(header, 6)? as usize,
+    };
+    let name_size: usize = hex_field(header, 11)? as usize;
+
+    let name_start: usize = offset + HEADER_SIZE;
+    let name: &[u8] = bytes
+        .get(name_start..name_start + name_size)
+        .ok_or("cpio archive truncated")?;
+    //namesize counts the nul
+    let name: &[u8] = match name.split_last() {
+        Some((0, name)) => name,
+        _ => return Err("bad cpio entry name"),
+    };
+    let name: &str = core::str::from_utf8(name).map_err(|_| "bad cpio entry name")?;
+    if name == TRAILER {
+        return Ok(None);
+    }
+
+    let data_start: usize = align4(name_start + name_size);
+    let data: &[u8] = bytes
+        .get(data_start..data_start + metadata.size)
+        .ok_or("cpio archive truncated")?;
+    let entry: Entry = Entry {
+        path: normalize(name),
+        metadata,
+        data,
+    };
+    Ok(Some((entry, align4(data_start + metadata.size))))
+}
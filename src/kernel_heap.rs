@@ -0,0 +1,193 @@
+// Kernel heap, the #[global_allocator] behind Box, Vec, String, BTreeMap, ...
+// memory_alloc only hands out whole pages, so small allocations are carved out of
+// slabs: a slab is one page holding a header and then equal sized blocks,
+// free blocks are kept in a linked list threaded through the blocks themselves
+// there is one list of slabs with free blocks per size class (16 bytes up to 2 KiB),
+// a new slab is allocated when a class runs out and given back once all its blocks are free
+// anything bigger than the largest class gets whole pages straight from memory_alloc
+
+use crate::memory_alloc;
+use crate::memory_alloc::PAGE_SIZE;
+use crate::trap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+
+const MIN_BLOCK_SHIFT: usize = 4;
+const NUM_CLASSES: usize = 8;
+const MAX_BLOCK_SIZE: usize = 1 << (MIN_BLOCK_SHIFT + NUM_CLASSES - 1);
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+//lives at the start of every slab page
+struct SlabHeader {
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    free: *mut FreeBlock,
+    used: usize,
+}
+
+static_assertions::const_assert!(core::mem::size_of::<SlabHeader>() <= (1 << MIN_BLOCK_SHIFT) * 2);
+
+fn block_size(class: usize) -> usize {
+    1 << (MIN_BLOCK_SHIFT + class)
+}
+
+//smallest class whose blocks are big enough and aligned enough, blocks are aligned to their size
+fn size_class(layout: &Layout) -> Option<usize> {
+    let wanted: usize = layout.size().max(layout.align()).max(1 << MIN_BLOCK_SHIFT);
+    if wanted > MAX_BLOCK_SIZE {
+        return None;
+    }
+    Some(wanted.next_power_of_two().trailing_zeros() as usize - MIN_BLOCK_SHIFT)
+}
+
+//the first block goes after the header, at an offset that keeps it aligned to its size
+fn first_block_offset(class: usize) -> usize {
+    let header_size: usize = core::mem::size_of::<SlabHeader>();
+    let size: usize = block_size(class);
+    header_size.div_ceil(size) * size
+}
+
+fn blocks_per_slab(class: usize) -> usize {
+    (PAGE_SIZE - first_block_offset(class)) / block_size(class)
+}
+
+struct Slabs {
+    //slabs with at least one free block, per class
+    partial: [*mut SlabHeader; NUM_CLASSES],
+}
+
+//only ever touched with the lock held
+unsafe impl Send for Slabs {}
+
+impl Slabs {
+    unsafe fn push_partial(&mut self, class: usize, slab: *mut SlabHeader) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial[class];
+        if !self.partial[class].is_null() {
+            (*self.partial[class]).prev = slab;
+        }
+        self.partial[class] = slab;
+    }
+
+    unsafe fn remove_partial(&mut self, class: usize, slab: *mut SlabHeader) {
+        if (*slab).prev.is_null() {
+            self.partial[class] = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        (*slab).prev = null_mut();
+        (*slab).next = null_mut();
+    }
+
+    unsafe fn new_slab(&mut self, class: usize) -> Option<*mut SlabHeader> {
+        let page: *mut u8 = memory_alloc::allocate_pages(1).ok()?;
+        let slab: *mut SlabHeader = page as *mut SlabHeader;
+        slab.write(SlabHeader {
+            prev: null_mut(),
+            next: null_mut(),
+            free: null_mut(),
+            used: 0,
+        });
+
+        //thread the free list through the blocks, lowest address first
+        for index in (0..blocks_per_slab(class)).rev() {
+            let block: *mut FreeBlock =
+                page.add(first_block_offset(class) + index * block_size(class)) as *mut FreeBlock;
+            (*block).next = (*slab).free;
+            (*slab).free = block;
+        }
+
+        self.push_partial(class, slab);
+        Some(slab)
+    }
+
+    unsafe fn alloc(&mut self, class: usize) -> *mut u8 {
+        let slab: *mut SlabHeader = if self.partial[class].is_null() {
+            match self.new_slab(class) {
+                Some(slab) => slab,
+                None => return null_mut(),
+            }
+        } else {
+            self.partial[class]
+        };
+
+        let block: *mut FreeBlock = (*slab).free;
+        assert!(!block.is_null());
+        (*slab).free = (*block).next;
+        (*slab).used += 1;
+
+        if (*slab).free.is_null() {
+            self.remove_partial(class, slab);
+        }
+        block as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, class: usize, ptr: *mut u8) {
+        let slab: *mut SlabHeader = (ptr as usize & !(PAGE_SIZE - 1)) as *mut SlabHeader;
+        let was_full: bool = (*slab).free.is_null();
+
+        let block: *mut FreeBlock = ptr as *mut FreeBlock;
+        (*block).next = (*slab).free;
+        (*slab).free = block;
+        assert!((*slab).used > 0);
+        (*slab).used -= 1;
+
+        if was_full {
+            self.push_partial(class, slab);
+        }
+        if (*slab).used == 0 {
+            self.remove_partial(class, slab);
+            memory_alloc::deallocate_pages(slab as *mut u8);
+        }
+    }
+}
+
+pub struct KernelHeap {
+    slabs: spin::Mutex<Slabs>,
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match size_class(&layout) {
+            //interrupt handlers may allocate too, don't let them spin on a lock we hold
+            Some(class) => trap::without_interrupts(|| self.slabs.lock().alloc(class)),
+            None => {
+                //pages are only page aligned
+                if layout.align() > PAGE_SIZE {
+                    return null_mut();
+                }
+                let num_pages: usize = layout.size().div_ceil(PAGE_SIZE);
+                memory_alloc::allocate_pages(num_pages).unwrap_or(null_mut())
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match size_class(&layout) {
+            Some(class) => trap::without_interrupts(|| self.slabs.lock().dealloc(class, ptr)),
+            None => memory_alloc::deallocate_pages(ptr),
+        }
+    }
+}
+
+#[global_allocator]
+static KERNEL_HEAP: KernelHeap = KernelHeap {
+    slabs: spin::Mutex::new(Slabs {
+        partial: [null_mut(); NUM_CLASSES],
+    }),
+};
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!(
+        "kernel heap out of memory allocating {} bytes (align {})",
+        layout.size(),
+        layout.align()
+    );
+}
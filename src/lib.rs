@@ -1,8 +1,26 @@
 #![no_std]
 #![feature(panic_info_message)]
+#![feature(alloc_error_handler)]
 
+extern crate alloc;
+
+use mmu::address_space::AddressSpace;
+
+mod clint;
+mod elf;
+mod fdt;
+mod initramfs;
+mod kernel_heap;
 mod memory_alloc;
 mod mmu;
+mod plic;
+mod process;
+mod sbi;
+mod scheduler;
+mod stack;
+mod syscall;
+mod thread;
+mod trap;
 mod uart;
 
 extern "C" {
@@ -25,14 +43,42 @@ extern "C" {
 
     static STACK_TOP: usize;
     static STACK_BOT: usize;
+    static STACK_GUARD: usize;
     static HEAP_START: usize;
     static HEAP_END: usize;
     static HEAP_SIZE: usize;
 
-    //syscon mmio
-    static SYSCON_ADDR: usize;
+    //mmio, defaults in symbols.S get replaced by what the device tree says
+    //syscon
+    static mut SYSCON_ADDR: usize;
+
+    static mut CLINT_ADDR: usize;
+    static mut PLIC_ADDR: usize;
+
+    static mut UART_ADDR: usize;
 
-    static UART_ADDR: usize;
+    //the test programs in user_programs.S
+    static USER_SYSCALLS_START: usize;
+    static USER_SYSCALLS_END: usize;
+    static USER_FAULT_START: usize;
+    static USER_FAULT_END: usize;
+}
+
+//how long a task runs before the scheduler lets the next one have the hart
+const TIME_SLICE: core::time::Duration = core::time::Duration::from_millis(10);
+
+//what the device tree told us about the machine, empty if there was no usable device tree
+static BOARD: spin::Once<fdt::BoardInfo> = spin::Once::new();
+
+pub fn board() -> &'static fdt::BoardInfo {
+    BOARD.call_once(fdt::BoardInfo::default)
+}
+
+//the hart kmain was started on, with sbi firmware this isn't necessarily hart 0
+static BOOT_HART: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+pub fn boot_hart() -> usize {
+    BOOT_HART.load(core::sync::atomic::Ordering::Relaxed)
 }
 
 #[no_mangle]
@@ -73,28 +119,47 @@ extern "C" fn abort() -> ! {
 lazy_static::lazy_static! {
     //since uart is a raw pointer we should manually protect from multithreading with a mutex
     //for now use a simple spin lock but this should be changed to something more efficient later
-    pub static ref WRITER: spin::Mutex<uart::UartWriter> = spin::Mutex::new(uart::UartWriter::new(unsafe{UART_ADDR}));
+    pub static ref WRITER: spin::Mutex<uart::UartWriter> = spin::Mutex::new(uart::UartWriter::new(mmu::phys_to_virt(unsafe{UART_ADDR})));
 
-    pub static ref MEMORY_RANGES: [(usize, usize); 6] = unsafe {
+    //(start, end, protection bits) of each part of the kernel image, at its linked (virtual) addresses
+    //nothing is both writable and executable
+    pub static ref MEMORY_RANGES: [(usize, usize, usize); 5] = unsafe {
+        let read: usize = mmu::page_table::PteBits::Read.val();
+        let write: usize = mmu::page_table::PteBits::Write.val();
+        let execute: usize = mmu::page_table::PteBits::Execute.val();
         [
-            (TEXT_START, TEXT_END),
-            (RODATA_START, RODATA_END),
-            (DATA_START, DATA_END),
-            (BSS_START, BSS_END),
-            (STACK_BOT, STACK_TOP),
-            (HEAP_START, HEAP_END),
+            (TEXT_START, TEXT_END, read | execute),
+            (RODATA_START, RODATA_END, read),
+            (DATA_START, DATA_END, read | write),
+            (BSS_START, BSS_END, read | write),
+            (STACK_BOT, STACK_TOP, read | write),
         ]
     };
 
-    pub static ref MEMORY_ADDRS: [usize; 2]= unsafe{
-        [UART_ADDR, SYSCON_ADDR]
+    //mmio (physical address, size) pairs
+    pub static ref MEMORY_ADDRS: [(usize, usize); 4]= unsafe{
+        [
+            (UART_ADDR, board().uart.map_or(0x100, |uart| uart.size)),
+            (SYSCON_ADDR, board().syscon.map_or(0x1000, |syscon| syscon.size)),
+            (CLINT_ADDR, board().clint.map_or(clint::CLINT_SIZE, |clint| clint.size)),
+            (PLIC_ADDR, board().plic.map_or(plic::PLIC_SIZE, |plic| plic.size)),
+        ]
     };
 
 }
 
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
+    //timer callbacks can print too, so don't let an interrupt spin on a lock we already hold
+    trap::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        //the lock also keeps lines from different callers apart on the sbi console
+        if sbi::has_console() {
+            sbi::SbiConsole.write_fmt(args).unwrap();
+        } else {
+            writer.write_fmt(args).unwrap();
+        }
+    });
 }
 
 //make our own print!() and println!() will go to UART output, since the standard library and stdout don't exist
@@ -111,25 +176,125 @@ macro_rules! println {
 
 fn poweroff() {
     println!("poweroff now");
+    if sbi::has_reset() {
+        let error: sbi::SbiError =
+            sbi::system_reset(sbi::ResetType::Shutdown, sbi::ResetReason::NoReason);
+        println!("sbi shutdown failed ({:?}), trying syscon", error);
+    }
     unsafe {
-        let syscon_ptr: *mut u32 = SYSCON_ADDR as *mut u32;
+        let syscon_ptr: *mut u32 = mmu::phys_to_virt(SYSCON_ADDR) as *mut u32;
         syscon_ptr.write_volatile(0x5555);
     }
 }
 
 fn reboot() {
     println!("reboot now");
+    if sbi::has_reset() {
+        let error: sbi::SbiError =
+            sbi::system_reset(sbi::ResetType::ColdReboot, sbi::ResetReason::NoReason);
+        println!("sbi reboot failed ({:?}), trying syscon", error);
+    }
     unsafe {
-        let syscon_ptr: *mut u32 = SYSCON_ADDR as *mut u32;
+        let syscon_ptr: *mut u32 = mmu::phys_to_virt(SYSCON_ADDR) as *mut u32;
         syscon_ptr.write_volatile(0x7777);
     }
 }
 
+//point the mmio addresses at whatever the device tree found
+fn apply_board_info(info: &fdt::BoardInfo) {
+    unsafe {
+        if let Some(uart) = info.uart {
+            UART_ADDR = uart.base;
+        }
+        if let Some(syscon) = info.syscon {
+            SYSCON_ADDR = syscon.base;
+        }
+        if let Some(clint) = info.clint {
+            CLINT_ADDR = clint.base;
+        }
+        if let Some(plic) = info.plic {
+            PLIC_ADDR = plic.base;
+        }
+    }
+}
+
+fn print_board_info(info: &fdt::BoardInfo) {
+    let print_device = |name: &str, device: Option<fdt::Device>| match device {
+        Some(device) => println!(
+            "{:<6} | {:#010x} -> {:#010x} irq {:?}",
+            name,
+            device.base,
+            device.base + device.size,
+            device.irq
+        ),
+        None => println!("{:<6} | not found, using default", name),
+    };
+    print_device("uart", info.uart);
+    print_device("syscon", info.syscon);
+    print_device("clint", info.clint);
+    print_device("plic", info.plic);
+    if let Some(frequency) = info.timebase_frequency {
+        println!("timebase frequency {} Hz", frequency);
+    }
+    if let Some(bootargs) = info.bootargs {
+        println!("bootargs: {}", bootargs);
+    }
+    if let Some(stdout_path) = info.stdout_path {
+        println!("stdout-path: {}", stdout_path);
+    }
+}
+
+//physical ram (start, end), falls back to the size in linker.ld without a device tree
+fn memory_bounds() -> (usize, usize) {
+    match board().memory {
+        Some((base, size)) => (base, base + size),
+        None => unsafe { (MEMORY_START, MEMORY_END) },
+    }
+}
+
+//the heap is the biggest stretch of ram after the kernel that the device tree and anything
+//the firmware reserved leave free, the allocator only manages one contiguous range
+//so ram on the other sides of a reservation in the middle of memory goes unused
+//every stretch starts at the end of the kernel or the end of a reservation and runs up to
+//the next reservation or the end of ram
+//physical, like everything the device tree says
+fn heap_bounds() -> (usize, usize) {
+    let page: usize = memory_alloc::PAGE_SIZE;
+    let memory_end: usize = memory_bounds().1;
+    let kernel_end: usize = mmu::virt_to_phys(unsafe { HEAP_START });
+    let reserved = || board().dtb.iter().chain(board().reserved.iter().flatten());
+    let starts = core::iter::once(kernel_end)
+        .chain(reserved().map(|(addr, size)| addr.saturating_add(*size)));
+
+    let mut best: (usize, usize) = (kernel_end, kernel_end);
+    for start in starts {
+        let start: usize = start.saturating_add(page - 1) / page * page;
+        if start < kernel_end || start >= memory_end {
+            continue;
+        }
+        //inside another reservation, whose end is a candidate of its own
+        if reserved().any(|(addr, size)| *addr <= start && start < addr.saturating_add(*size)) {
+            continue;
+        }
+        let mut end: usize = memory_end;
+        for (addr, _) in reserved() {
+            if *addr >= start && *addr < end {
+                end = *addr;
+            }
+        }
+        let end: usize = end - (end % page);
+        if end > start && end - start > best.1 - best.0 {
+            best = (start, end);
+        }
+    }
+    best
+}
+
 fn print_memory_layout() {
+    let (memory_start, memory_end) = memory_bounds();
     println!(
-        "Memory | {:#010x} -> {:#010x}",
-        unsafe { MEMORY_START },
-        unsafe { MEMORY_END }
+        "Memory | {:#010x} -> {:#010x} (physical)",
+        memory_start, memory_end
     );
     println!(
         "Text   | {:#010x} -> {:#010x}",
@@ -151,98 +316,806 @@ fn print_memory_layout() {
         unsafe { BSS_START },
         unsafe { BSS_END }
     );
+    println!(
+        "Guard  | {:#010x} -> {:#010x}",
+        unsafe { STACK_GUARD },
+        unsafe { STACK_BOT }
+    );
     println!(
         "Stack  | {:#010x} -> {:#010x}",
         unsafe { STACK_BOT },
         unsafe { STACK_TOP }
     );
+    let (heap_start, heap_end) = heap_bounds();
+    println!(
+        "Heap   | {:#010x} -> {:#010x} (physical)",
+        heap_start, heap_end
+    );
     println!(
-        "Heap   | {:#010x} -> {:#010x}",
-        unsafe { HEAP_START },
-        unsafe { HEAP_END }
+        "Direct | {:#010x} -> {:#010x}",
+        mmu::phys_to_virt(memory_start),
+        mmu::phys_to_virt(memory_end)
     );
     assert!(unsafe { HEAP_SIZE == HEAP_END - HEAP_START });
 }
 
-fn memory_map_important_stuff(root_table: &mut mmu::sv39::PageTable) {
-    for pair in MEMORY_RANGES.iter() {
+//the parts of physical memory the direct map covers, ram except the kernel image,
+//which is only reachable at its linked address so .text never gets a writable alias
+//it starts right after the image and not at the heap, board() borrows from the dtb which
+//can sit in between
+fn direct_map_ranges() -> [(usize, usize); 2] {
+    let (memory_start, memory_end) = memory_bounds();
+    [
+        (memory_start, mmu::virt_to_phys(unsafe { TEXT_START })),
+        (mmu::virt_to_phys(unsafe { HEAP_START }), memory_end),
+    ]
+}
+
+//kmain runs in supervisor mode so these mappings are enforced once the mmu is on
+//the stack guard page between .bss and the stack is deliberately left out
+//nothing is mapped in the lower half, that is left for user space
+fn memory_map_important_stuff(root_table: &mut mmu::page_table::PageTable) {
+    let read: usize = mmu::page_table::PteBits::Read.val();
+    let write: usize = mmu::page_table::PteBits::Write.val();
+
+    for range in MEMORY_RANGES.iter() {
+        mmu::memory_map_region(range.0, range.1, root_table, range.2);
+    }
+
+    for (start, end) in direct_map_ranges() {
         mmu::memory_map_region(
-            pair.0,
-            pair.1,
+            mmu::phys_to_virt(start),
+            mmu::phys_to_virt(end),
             root_table,
-            mmu::sv39::PteBits::Read.val() | mmu::sv39::PteBits::Execute.val(),
+            read | write,
         );
     }
 
-    for addr in MEMORY_ADDRS.iter() {
+    for pair in MEMORY_ADDRS.iter() {
         mmu::memory_map_region(
-            *addr,
-            *addr + 1,
+            mmu::phys_to_virt(pair.0),
+            mmu::phys_to_virt(pair.0 + pair.1),
             root_table,
-            mmu::sv39::PteBits::Read.val() | mmu::sv39::PteBits::Execute.val(),
+            read | write,
         );
     }
 }
 
-fn test_memory_map(root_table: &mut mmu::sv39::PageTable) {
-    for pair in MEMORY_RANGES.iter() {
+fn test_memory_map(root_table: &mmu::page_table::PageTable) {
+    for range in MEMORY_RANGES.iter() {
         //test some random addresses, just chose a random prime number here
-        for addr in ((pair.0)..(pair.1)).step_by(971) {
-            assert!(addr == (mmu::sv39::virt_to_phys(addr, root_table).unwrap() as usize));
+        for addr in ((range.0)..(range.1)).step_by(971) {
+            assert!(
+                mmu::virt_to_phys(addr)
+                    == (mmu::page_table::virt_to_phys(addr, root_table).unwrap() as usize)
+            );
+        }
+    }
+
+    for (start, end) in direct_map_ranges() {
+        //big ranges, so a bigger prime
+        for addr in (start..end).step_by(999_983) {
+            let va: usize = mmu::phys_to_virt(addr);
+            assert!(addr == (mmu::page_table::virt_to_phys(va, root_table).unwrap() as usize));
+        }
+    }
+
+    let guard: usize = unsafe { STACK_GUARD };
+    for addr in (guard..(guard + 4096)).step_by(971) {
+        assert!(mmu::page_table::virt_to_phys(addr, root_table).is_err());
+    }
+
+    for pair in MEMORY_ADDRS.iter() {
+        for addr in ((pair.0)..(pair.0 + pair.1)).step_by(4096) {
+            let va: usize = mmu::phys_to_virt(addr);
+            assert!(addr == (mmu::page_table::virt_to_phys(va, root_table).unwrap() as usize));
+        }
+    }
+
+    //and the layout as a whole
+    let write: usize = mmu::page_table::PteBits::Write.val();
+    let execute: usize = mmu::page_table::PteBits::Execute.val();
+    for mapping in mmu::page_table::mappings(root_table) {
+        assert!((mapping.va as isize) < 0, "kernel mapping in the user half");
+        assert!(mapping.flags & (write | execute) != (write | execute));
+    }
+}
+
+//map, unmap and drop a throwaway address space, it is never activated
+fn test_address_space() {
+    let read_write: usize =
+        mmu::page_table::PteBits::Read.val() | mmu::page_table::PteBits::Write.val();
+    let before: memory_alloc::AllocStats = memory_alloc::stats();
+    {
+        let mut space: AddressSpace = AddressSpace::new().unwrap();
+        space.map_new(0x1000_0000, 4 * 4096, read_write).unwrap();
+        assert!(space.owned_pages() == 4);
+        assert!(space.map_new(0x1000_3000, 4096, read_write).is_err());
+        assert!(space.translate(0x1000_2000).is_ok());
+
+        space.unmap(0x1000_0000, 2 * 4096).unwrap();
+        assert!(space.owned_pages() == 2);
+        assert!(space.translate(0x1000_0000).is_err());
+        assert!(space.translate(0x1000_2000).is_ok());
+        //dropping frees the rest
+    }
+    memory_alloc::assert_no_leaks_since(&before);
+}
+
+//unmap, protect and remap ranges in the middle of a mapping, the neighbours on both sides
+//must come through untouched, and a range that can't be done must leave everything as it was
+fn test_range_ops() {
+    use mmu::page_table::{self, PteBits};
+
+    let read: usize = PteBits::Read.val();
+    let write: usize = PteBits::Write.val();
+    let flags = |space: &AddressSpace, va: usize| -> usize {
+        page_table::leaf(space.root(), va).unwrap().flags & (read | write)
+    };
+    let before: memory_alloc::AllocStats = memory_alloc::stats();
+    {
+        let mut space: AddressSpace = AddressSpace::new().unwrap();
+        let base: usize = 0x2000_0000;
+        space.map_new(base, 6 * 4096, read | write).unwrap();
+
+        space.unmap(base + 4096, 2 * 4096).unwrap();
+        assert!(space.translate(base).is_ok());
+        assert!(space.translate(base + 4096).is_err());
+        assert!(space.translate(base + 2 * 4096).is_err());
+        assert!(space.translate(base + 3 * 4096).is_ok());
+        assert!(space.owned_pages() == 4);
+
+        space.protect(base + 3 * 4096, 2 * 4096, read).unwrap();
+        assert!(flags(&space, base) == read | write);
+        assert!(flags(&space, base + 3 * 4096) == read);
+        assert!(flags(&space, base + 4 * 4096) == read);
+        assert!(flags(&space, base + 5 * 4096) == read | write);
+        //the hole makes it fail, before anything was changed
+        assert!(space.protect(base, 4 * 4096, read).is_err());
+        assert!(flags(&space, base) == read | write);
+
+        let original: usize = space.translate(base).unwrap();
+        let moved: usize = space.translate(base + 5 * 4096).unwrap();
+        let asid: usize = space.asid();
+        //no read, write or execute would make the leaf a branch to moved
+        assert!(page_table::remap(space.root_mut(), base, moved, 0, asid).is_err());
+        assert!(space.translate(base) == Ok(original));
+        page_table::remap(space.root_mut(), base, moved, read, asid).unwrap();
+        assert!(space.translate(base) == Ok(moved));
+        assert!(flags(&space, base) == read);
+        assert!(space.translate(base + 5 * 4096) == Ok(moved));
+        //put it back so drop frees the right frames
+        page_table::remap(space.root_mut(), base, original, read | write, asid).unwrap();
+
+        //a megapage (not owned) can only go as a whole
+        let mega: usize = 0x4000_0000;
+        space.map(mega, 0x8000_0000, 1 << 21, read);
+        assert!(space.unmap(mega + 4096, 4096).is_err());
+        assert!(space.protect(mega, 4096, read | write).is_err());
+        assert!(space.translate(mega + 4096) == Ok(0x8000_1000));
+        space.unmap(mega, 1 << 21).unwrap();
+        assert!(space.translate(mega).is_err());
+    }
+    memory_alloc::assert_no_leaks_since(&before);
+}
+
+//reserve a big range in the (active) kernel address space and touch a couple of pages,
+//only those should get frames
+//the fault handler borrows the active space mutably, so no reference to it may be alive while
+//the test touches the range, every call goes through the raw pointer and ends right away
+fn test_demand_paging() {
+    let space: *mut AddressSpace = mmu::address_space::active();
+    let read_write: usize =
+        mmu::page_table::PteBits::Read.val() | mmu::page_table::PteBits::Write.val();
+    //nothing of the kernel's lives in the lower half
+    let lazy_start: usize = 0x4000_0000;
+    let lazy_len: usize = 1 << 30;
+    unsafe {
+        (*space).reserve(lazy_start, lazy_len, read_write).unwrap();
+        assert!((*space)
+            .reserve(lazy_start + 4096, 4096, read_write)
+            .is_err());
+    }
+    let pages_before: usize = unsafe { (*space).owned_pages() };
+
+    let first: *mut u64 = lazy_start as *mut u64;
+    let last: *mut u64 = (lazy_start + lazy_len - 8) as *mut u64;
+    unsafe {
+        assert!(first.read_volatile() == 0);
+        first.write_volatile(0x1234);
+        last.write_volatile(0x5678);
+        assert!(first.read_volatile() == 0x1234);
+        assert!(last.read_volatile() == 0x5678);
+    }
+    unsafe {
+        assert!((*space).owned_pages() == pages_before + 2);
+
+        (*space).release(lazy_start).unwrap();
+        assert!((*space).owned_pages() == pages_before);
+        assert!((*space).translate(lazy_start).is_err());
+    }
+}
+
+//fork an address space that is never activated and play the store faults by hand
+fn test_copy_on_write() {
+    use mmu::address_space::Access;
+
+    let read_write: usize =
+        mmu::page_table::PteBits::Read.val() | mmu::page_table::PteBits::Write.val();
+    let before: memory_alloc::AllocStats = memory_alloc::stats();
+    let va: usize = 0x1000_0000;
+    //neither space is active, so look at their memory through the direct map
+    let word = |space: &AddressSpace| -> *mut u64 {
+        mmu::phys_to_virt(space.translate(va).unwrap()) as *mut u64
+    };
+    {
+        let mut parent: AddressSpace = AddressSpace::new().unwrap();
+        parent.map_new(va, 4096, read_write).unwrap();
+        unsafe { word(&parent).write_volatile(42) };
+
+        let mut child: AddressSpace = parent.fork().unwrap();
+        assert!(parent.translate(va) == child.translate(va));
+        let frame: *mut u8 = word(&parent) as *mut u8;
+        assert!(memory_alloc::page_refs(frame) == 2);
+        //both lost write access, the first store from either one faults
+        let write: usize = mmu::page_table::PteBits::Write.val();
+        for space in [&parent, &child] {
+            let mapping = mmu::page_table::leaf(space.root(), va).unwrap();
+            assert!(mapping.flags & write == 0);
+        }
+        //a read of a mapped page faulting is a genuine fault
+        assert!(parent.handle_page_fault(va, Access::Read).is_err());
+
+        child.handle_page_fault(va, Access::Write).unwrap();
+        assert!(parent.translate(va) != child.translate(va));
+        assert!(memory_alloc::page_refs(frame) == 1);
+        unsafe {
+            assert!(word(&child).read_volatile() == 42);
+            word(&child).write_volatile(7);
+            assert!(word(&parent).read_volatile() == 42);
+        }
+
+        //nobody else shares the frame now, so the parent just gets it back writable
+        let parent_pa: usize = parent.translate(va).unwrap();
+        parent.handle_page_fault(va, Access::Write).unwrap();
+        assert!(parent.translate(va).unwrap() == parent_pa);
+    }
+    memory_alloc::assert_no_leaks_since(&before);
+}
+
+//demand page a clean and a dirty page into a space that is never activated, age them and
+//check that only the clean one is reclaimed
+fn test_reclaim() {
+    use mmu::address_space::Access;
+
+    let read_write: usize =
+        mmu::page_table::PteBits::Read.val() | mmu::page_table::PteBits::Write.val();
+    let before: memory_alloc::AllocStats = memory_alloc::stats();
+    let clean: usize = 0x2000_0000;
+    let dirty: usize = clean + 4096;
+    {
+        let mut space: AddressSpace = AddressSpace::new().unwrap();
+        space.reserve(clean, 2 * 4096, read_write).unwrap();
+        space.handle_page_fault(clean, Access::Read).unwrap();
+        space.handle_page_fault(dirty, Access::Write).unwrap();
+        let word: *mut u64 = mmu::phys_to_virt(space.translate(dirty).unwrap()) as *mut u64;
+        unsafe { word.write_volatile(99) };
+        assert!(space.owned_pages() == 2);
+
+        //both were just touched, the first sweep only takes their Accessed bits away
+        assert!(space.reclaim(2) == 0);
+        assert!(space.harvest_accessed().is_empty());
+        //a load faults on the clear Accessed bit, once
+        space.handle_page_fault(clean, Access::Read).unwrap();
+        assert!(space.handle_page_fault(clean, Access::Read).is_err());
+        assert!(space.harvest_accessed() == alloc::vec![clean]);
+
+        //cold now, but the dirty page has content nobody else has a copy of
+        assert!(space.reclaim(2) == 1);
+        assert!(space.owned_pages() == 1);
+        assert!(space.translate(clean).is_err());
+        assert!(space.translate(dirty).is_ok());
+
+        //touching it again brings back zeroes
+        space.handle_page_fault(clean, Access::Read).unwrap();
+        let word: *mut u64 = mmu::phys_to_virt(space.translate(clean).unwrap()) as *mut u64;
+        assert!(unsafe { word.read_volatile() } == 0);
+    }
+    memory_alloc::assert_no_leaks_since(&before);
+}
+
+//the bytes of a program in user_programs.S
+fn user_program(start: usize, end: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(start as *const u8, end - start) }
+}
+
+//run the test programs from user_programs.S, one goes through the syscalls and exits,
+//the other gets killed for reading kernel memory
+fn test_process(kernel_space: &AddressSpace) {
+    use process::{Exit, Process, State};
+
+    let before: memory_alloc::AllocStats = memory_alloc::stats();
+    {
+        let syscalls: &[u8] =
+            user_program(unsafe { USER_SYSCALLS_START }, unsafe { USER_SYSCALLS_END });
+        let mut process: Process = Process::new(kernel_space, syscalls).unwrap();
+        //it wrote its greeting and yielded with its pid in s0
+        assert!(process.run() == Ok(Exit::Yielded));
+        assert!(process.frame().regs[8] == process.pid());
+        //the kernel address space is back
+        assert!(kernel_space.is_active());
+
+        assert!(process.run() == Ok(Exit::Yielded));
+        let mut word: [u8; 8] = [0; 8];
+        let heap: usize = process::USER_HEAP_START;
+        process.space_mut().copy_from_user(heap, &mut word).unwrap();
+        assert!(u64::from_le_bytes(word) == 42);
+        //the break is enforced, the page above it isn't part of the heap
+        assert!(process
+            .space_mut()
+            .copy_from_user(heap + 4096, &mut word)
+            .is_err());
+        //clock_gettime worked, the bad pointer and the bad number were turned away
+        let regs: &[usize; 32] = &process.frame().regs;
+        assert!(regs[9] == 0);
+        assert!(regs[18] as isize == -(syscall::Errno::Fault as isize));
+        assert!(regs[19] as isize == -(syscall::Errno::NoSys as isize));
+        //text, a heap page and a stack page
+        assert!(process.space().owned_pages() == 3);
+
+        assert!(process.run() == Ok(Exit::Exited(7)));
+        assert!(process.state() == State::Exited(7));
+        assert!(process.run().is_err());
+        //shrinking the heap gives its page back, growing it again brings zeroes
+        assert!(process.sbrk(-4096) == Ok(heap + 4096));
+        assert!(process.space().owned_pages() == 2);
+        assert!(process.space_mut().copy_from_user(heap, &mut word).is_err());
+        assert!(process.sbrk(8) == Ok(heap));
+        process.space_mut().copy_from_user(heap, &mut word).unwrap();
+        assert!(word == [0; 8]);
+
+        let fault: &[u8] = user_program(unsafe { USER_FAULT_START }, unsafe { USER_FAULT_END });
+        let mut process: Process = Process::new(kernel_space, fault).unwrap();
+        let killed: Exit = process.run().unwrap();
+        assert!(killed == Exit::Killed(trap::Exception::LoadPageFault));
+        assert!(process.state() == State::Killed(trap::Exception::LoadPageFault));
+    }
+    memory_alloc::assert_no_leaks_since(&before);
+}
+
+//p_flags, p_vaddr, contents and p_memsz of a PT_LOAD segment
+type TestSegment<'a> = (u32, usize, &'a [u8], usize);
+
+//an ELF executable with one PT_LOAD segment per TestSegment
+fn elf_image(entry: usize, segments: &[TestSegment]) -> alloc::vec::Vec<u8> {
+    use alloc::vec::Vec;
+
+    let mut image: Vec<u8> = Vec::new();
+    image.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+    image.resize(16, 0);
+    image.extend_from_slice(&2u16.to_le_bytes()); //ET_EXEC
+    image.extend_from_slice(&243u16.to_le_bytes()); //EM_RISCV
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&(entry as u64).to_le_bytes());
+    image.extend_from_slice(&64u64.to_le_bytes()); //program headers right after
+    image.extend_from_slice(&0u64.to_le_bytes()); //no section headers
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend_from_slice(&64u16.to_le_bytes());
+    image.extend_from_slice(&56u16.to_le_bytes());
+    image.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+    image.extend_from_slice(&[0; 6]);
+
+    let mut offset: usize = 64 + 56 * segments.len();
+    for &(flags, vaddr, contents, mem_size) in segments {
+        image.extend_from_slice(&1u32.to_le_bytes()); //PT_LOAD
+        image.extend_from_slice(&flags.to_le_bytes());
+        for field in [offset, vaddr, vaddr, contents.len(), mem_size, 0x1000] {
+            image.extend_from_slice(&(field as u64).to_le_bytes());
+        }
+        offset += contents.len();
+    }
+    for &(_, _, contents, _) in segments {
+        image.extend_from_slice(contents);
+    }
+    image
+}
+
+//wrap the syscalls test program in an ELF executable with a bss and run it,
+//then make sure broken images are turned away
+fn test_elf(kernel_space: &AddressSpace) {
+    use alloc::vec::Vec;
+    use elf::ElfError;
+    use process::{Exit, Process};
+
+    const R: u32 = 4;
+    const W: u32 = 2;
+    const X: u32 = 1;
+    let text: usize = process::USER_TEXT;
+    let bss: usize = 0x40_0000;
+    let program: &[u8] = user_program(unsafe { USER_SYSCALLS_START }, unsafe { USER_SYSCALLS_END });
+
+    let before: memory_alloc::AllocStats = memory_alloc::stats();
+    {
+        let image: Vec<u8> = elf_image(
+            text,
+            &[
+                (R | X, text, program, program.len()),
+                (R | W, bss, &[], 0x2000),
+            ],
+        );
+        let mut process: Process = Process::from_elf(kernel_space, &image).unwrap();
+        let mut word: [u8; 8] = [0xff; 8];
+        process
+            .space_mut()
+            .copy_from_user(bss + 0x1ff8, &mut word)
+            .unwrap();
+        assert!(word == [0; 8]);
+        //text isn't writable, the bss isn't executable
+        let text_flags: usize = mmu::page_table::leaf(process.space().root(), text)
+            .unwrap()
+            .flags;
+        let bss_flags: usize = mmu::page_table::leaf(process.space().root(), bss)
+            .unwrap()
+            .flags;
+        assert!(text_flags & mmu::page_table::PteBits::Write.val() == 0);
+        assert!(bss_flags & mmu::page_table::PteBits::Execute.val() == 0);
+        loop {
+            match process.run() {
+                Ok(Exit::Yielded) => {}
+                exit => {
+                    assert!(exit == Ok(Exit::Exited(7)));
+                    break;
+                }
+            }
+        }
+
+        let image: Vec<u8> = elf_image(text, &[(R | X, text, program, program.len())]);
+        assert!(elf::validate(&image) == Ok(text));
+        assert!(elf::validate(&image[..40]) == Err(ElfError::TooShort));
+        let mut broken: Vec<u8> = image.clone();
+        broken[1] = b'e';
+        assert!(elf::validate(&broken) == Err(ElfError::BadMagic));
+        let mut broken: Vec<u8> = image.clone();
+        broken[18] = 62; //x86-64
+        assert!(elf::validate(&broken) == Err(ElfError::WrongMachine));
+        let mut broken: Vec<u8> = image.clone();
+        broken.truncate(image.len() - 1);
+        assert!(elf::validate(&broken) == Err(ElfError::SegmentOutOfFile));
+
+        let heap: usize = process::USER_HEAP_START;
+        let checks: [(usize, &[TestSegment], ElfError); 6] = [
+            (
+                text,
+                &[(R | X, text, program, 4)],
+                ElfError::SegmentTooSmall,
+            ),
+            (
+                text,
+                &[(R | W, text, program, program.len())],
+                ElfError::BadEntry,
+            ),
+            (
+                text,
+                &[(0, text, program, program.len())],
+                ElfError::BadFlags,
+            ),
+            (
+                mmu::KERNEL_VIRT_BASE,
+                &[(R | X, mmu::KERNEL_VIRT_BASE, program, program.len())],
+                ElfError::BadAddress,
+            ),
+            (
+                heap,
+                &[(R | X, heap, program, program.len())],
+                ElfError::BadAddress,
+            ),
+            (
+                text,
+                &[
+                    (R | X, text, program, program.len()),
+                    (R | W, text + 8, &[], 8),
+                ],
+                ElfError::Overlap,
+            ),
+        ];
+        for (entry, segments, error) in checks {
+            let image: Vec<u8> = elf_image(entry, segments);
+            assert!(elf::validate(&image) == Err(error));
+            assert!(Process::from_elf(kernel_space, &image).is_err());
+        }
+    }
+    memory_alloc::assert_no_leaks_since(&before);
+}
+
+//list what the build packed into the initramfs, read a file and run bin/hello out of it
+fn test_initramfs(kernel_space: &AddressSpace) {
+    use initramfs::{Archive, Entry, Metadata};
+    use process::{Exit, Process};
+
+    let archive: Archive = initramfs::archive();
+    for entry in archive.entries() {
+        let entry: Entry = entry.unwrap();
+        println!(
+            "  {:06o} {:>6} {}",
+            entry.metadata.mode, entry.metadata.size, entry.path
+        );
+    }
+
+    let motd: &[u8] = archive.read("/etc/motd").unwrap();
+    assert!(motd.starts_with(b"welcome"));
+    assert!(archive.metadata("etc").unwrap().is_dir());
+    assert!(archive.read("etc").is_err());
+    assert!(archive.find("etc/nothing").is_err());
+
+    let hello: Metadata = archive.metadata("bin/hello").unwrap();
+    assert!(hello.is_file());
+    let image: &[u8] = archive.read("bin/hello").unwrap();
+    assert!(image.len() == hello.size);
+    let before: memory_alloc::AllocStats = memory_alloc::stats();
+    {
+        let mut process: Process = Process::from_elf(kernel_space, image).unwrap();
+        //it exits with a word from its bss
+        assert!(process.run() == Ok(Exit::Exited(0)));
+    }
+    memory_alloc::assert_no_leaks_since(&before);
+
+    //a cut off archive ends in an error instead of the trailer
+    let bytes: &[u8] = archive.bytes();
+    let cut: Archive = Archive::new(&bytes[..bytes.len() / 2]);
+    assert!(cut.entries().any(|entry| entry.is_err()));
+    assert!(Archive::new(b"not an archive")
+        .entries()
+        .next()
+        .unwrap()
+        .is_err());
+}
+
+//two tasks that never yield, each one spins until it sees the other one make progress,
+//which only happens if the timer takes the hart away from it
+fn test_scheduler() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+    fn spin(me: usize) {
+        let give_up: clint::Instant = clint::Instant::now() + core::time::Duration::from_secs(1);
+        while COUNTS[1 - me].load(Ordering::Relaxed) == 0 {
+            COUNTS[me].fetch_add(1, Ordering::Relaxed);
+            assert!(
+                clint::Instant::now() < give_up,
+                "task {} was never preempted",
+                scheduler::current_name()
+            );
         }
     }
+    fn spin_0() {
+        spin(0);
+    }
+    fn spin_1() {
+        spin(1);
+    }
 
-    for addr in MEMORY_ADDRS.iter() {
-        assert!(*addr == (mmu::sv39::virt_to_phys(*addr, root_table).unwrap() as usize));
+    let tasks: usize = scheduler::task_count();
+    scheduler::spawn("spin 0", spin_0).unwrap();
+    scheduler::spawn("spin 1", spin_1).unwrap();
+    while scheduler::task_count() > tasks {
+        scheduler::yield_now();
     }
+    assert!(COUNTS.iter().all(|count| count.load(Ordering::Relaxed) > 0));
 }
+
+//threads hand back what they returned through join, sleepers wake up once their time is up
+fn test_threads() {
+    use alloc::vec::Vec;
+    use core::time::Duration;
+
+    let sum = |n: usize| -> usize { (0..=n).sum() };
+    let handles: Vec<thread::JoinHandle<usize>> = (0..4)
+        .map(|i| {
+            thread::Builder::new()
+                .name("worker")
+                .spawn(move || {
+                    assert!(thread::current().name() == "worker");
+                    thread::yield_now();
+                    sum(i * 100)
+                })
+                .unwrap()
+        })
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert!(handle.name() == "worker" && handle.id() != thread::current().id());
+        assert!(handle.join() == sum(i * 100));
+    }
+
+    let sleeper: thread::JoinHandle<Duration> = thread::spawn(|| {
+        let start: clint::Instant = clint::Instant::now();
+        thread::sleep(Duration::from_millis(20));
+        start.elapsed()
+    });
+    assert!(sleeper.join() >= Duration::from_millis(20));
+
+    //the page below a stack is the guard, it stays unmapped
+    let stack: stack::Stack = stack::Stack::new().unwrap();
+    let space: &AddressSpace = unsafe { &*mmu::address_space::active() };
+    assert!(space.translate(stack.bottom()).is_ok());
+    assert!(space
+        .translate(stack.bottom() - memory_alloc::PAGE_SIZE)
+        .is_err());
+}
+
+//the syscall test program, as a task of its own next to the console
+fn user_task() {
+    use process::{Exit, Process};
+
+    //a task starts out in the address space it was spawned from, the kernel's
+    let kernel_space: &AddressSpace = unsafe { &*mmu::address_space::active() };
+    let program: &[u8] = user_program(unsafe { USER_SYSCALLS_START }, unsafe { USER_SYSCALLS_END });
+    let mut process: process::Process = Process::new(kernel_space, program).unwrap();
+    loop {
+        match process.run().unwrap() {
+            Exit::Yielded => scheduler::yield_now(),
+            exit => {
+                println!("process {} is done: {:?}", process.pid(), exit);
+                return;
+            }
+        }
+    }
+}
+
+fn test_kernel_heap() {
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeMap;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    let boxed: Box<u64> = Box::new(0xdead_beef);
+    assert!(*boxed == 0xdead_beef);
+
+    //big enough to need whole pages as it grows
+    let mut numbers: Vec<usize> = Vec::new();
+    for i in 0..10_000 {
+        numbers.push(i);
+    }
+    assert!(numbers.iter().sum::<usize>() == 10_000 * 9_999 / 2);
+
+    let mut words: BTreeMap<usize, String> = BTreeMap::new();
+    for i in 0..100 {
+        words.insert(i, alloc::format!("word {}", i));
+    }
+    assert!(words[&42] == "word 42");
+}
+
 //program entry point
 //assembly should jump to here, if everything goes right then now rust takes over
 #[no_mangle]
-extern "C" fn kmain() {
+extern "C" fn kmain(hartid: usize, dtb_addr: usize) {
+    BOOT_HART.store(hartid, core::sync::atomic::Ordering::Relaxed);
+    sbi::init();
+
+    let board_info: Result<fdt::BoardInfo, &str> = unsafe { fdt::BoardInfo::from_addr(dtb_addr) };
+    if let Ok(info) = board_info {
+        apply_board_info(&info);
+        BOARD.call_once(|| info);
+    }
+
+    println!("booted on hart {}", hartid);
+    if sbi::is_present() {
+        let (major, minor) = sbi::get_spec_version().unwrap();
+        println!(
+            "running under sbi {}.{}, implementation {:?} version {:#x}",
+            major,
+            minor,
+            sbi::get_impl_id(),
+            sbi::get_impl_version().unwrap_or(0)
+        );
+    } else {
+        println!("no sbi firmware, running bare metal");
+    }
+    match board_info {
+        Ok(info) => print_board_info(&info),
+        Err(error) => println!(
+            "no usable device tree at {:#010x} ({}), using qemu virt defaults",
+            dtb_addr, error
+        ),
+    }
     print_memory_layout();
 
+    println!("installing trap vector");
+    trap::init();
+
     println!("initializing memory management");
-    memory_alloc::init();
+    let (heap_start, heap_end) = heap_bounds();
+    memory_alloc::init(heap_start, heap_end);
     memory_alloc::print_page_allocation();
+    let boot_stats: memory_alloc::AllocStats = memory_alloc::stats();
 
-    println!("creating root table");
-    let root_table: &mut mmu::sv39::PageTable = unsafe {
-        (memory_alloc::zero_allocate_pages(1).unwrap() as *mut mmu::sv39::PageTable)
-            .as_mut()
-            .unwrap()
-    };
+    println!("testing kernel heap");
+    test_kernel_heap();
+
+    let paging_mode: &dyn mmu::paging::PagingMode = mmu::paging::probe();
+    println!("using {} paging", paging_mode.name());
+
+    println!("creating kernel address space");
+    let mut kernel_space: AddressSpace = AddressSpace::new().unwrap();
     println!("initializing memory mapping");
-    memory_map_important_stuff(root_table);
+    memory_map_important_stuff(kernel_space.root_mut());
     println!("testing map integrity");
-    test_memory_map(root_table);
+    test_memory_map(kernel_space.root());
     println!("done");
-    println!("enabling mmu");
-    mmu::enable_mmu(root_table as *const mmu::sv39::PageTable);
-    println!("hopefully everything went right");
+    mmu::page_table::dump(kernel_space.root());
+    println!("enabling mmu, asid {}", kernel_space.asid());
+    kernel_space.activate();
+    println!("hopefully everything went right, the boot page table is gone");
+
+    println!("testing address spaces");
+    test_address_space();
+    println!("testing range unmap/protect/remap");
+    test_range_ops();
+    println!("testing demand paging");
+    test_demand_paging();
+    println!("testing copy on write");
+    test_copy_on_write();
+    println!("testing page reclaim");
+    test_reclaim();
+    println!("testing user processes");
+    test_process(&kernel_space);
+    println!("testing elf loading");
+    test_elf(&kernel_space);
+    println!("testing the initramfs");
+    test_initramfs(&kernel_space);
+
+    println!("starting timer");
+    clint::init(board().timebase_frequency);
+    println!("sleeping for 1 second");
+    let before: clint::Instant = clint::Instant::now();
+    clint::sleep(core::time::Duration::from_secs(1));
+    println!("slept for {:?}", before.elapsed());
 
+    println!("enabling uart interrupts");
+    plic::init();
+    let uart_irq: u32 = board().uart.and_then(|uart| uart.irq).unwrap_or(uart::UART_IRQ);
+    plic::register_handler(uart_irq, 1, uart::handle_interrupt).unwrap();
+
+    stack::init(&mut kernel_space);
+    scheduler::init().unwrap();
+    println!(
+        "started the scheduler, kmain is task {}",
+        scheduler::current_id()
+    );
+    scheduler::start(TIME_SLICE).unwrap();
+    println!("testing preemption");
+    test_scheduler();
+    println!("testing threads");
+    test_threads();
+    scheduler::spawn("user", user_task).unwrap();
+
+    //the console loop sleeps in read_byte_wait while the other tasks run
     loop {
-        let uart_byte: Option<u8> = WRITER.lock().uart_read_byte();
-        if let Some(byte) = uart_byte {
-            println!("read char {}", byte);
-            if byte == b'p' {
-                poweroff();
-            }
-            if byte == b'r' {
-                reboot();
-            }
-            if byte == b'b' {
-                break;
-            }
-        } else {
-            //println!("read nothing");
+        let byte: u8 = uart::read_byte_wait();
+        println!("read char {}", byte);
+        if byte == b'p' {
+            poweroff();
+        }
+        if byte == b'r' {
+            reboot();
         }
+        if byte == b'b' {
+            break;
+        }
+    }
+
+    println!("waiting for the other tasks");
+    while scheduler::task_count() > 1 {
+        scheduler::yield_now();
     }
+    scheduler::stop();
+    stack::deinit();
 
     println!("unmapping virtual memory");
-    mmu::sv39::unmap(root_table);
-    memory_alloc::deallocate_pages((root_table as *mut mmu::sv39::PageTable) as *mut u8);
+    mmu::use_boot_table();
+    drop(kernel_space);
     memory_alloc::print_page_allocation();
-    println!("heap allocations on shutdown, should be zero pages allocated");
+    memory_alloc::stats().print();
+    memory_alloc::assert_no_leaks_since(&boot_stats);
+    println!("no pages leaked");
     poweroff();
 }
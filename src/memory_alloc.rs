@@ -1,11 +1,37 @@
+// Physical page allocator
+// the heap starts with an array of Page descriptors, one per allocatable page,
+// followed by the pages themselves starting at ALLOC_START
+// free memory is tracked buddy style: free blocks are 2^order pages long and start at
+// a page index that is a multiple of 2^order, there is a free list per order
+// allocating splits the smallest big enough block in halves until it fits,
+// freeing merges a block with its buddy (the other half it was split from) while that is free
+// allocations don't have to be a power of two, the unused tail of the block is freed again,
+// so every allocated run is marked Taken and its last page Last like before
+// the first page of a run also counts references to it, so a frame can be shared (copy on
+// write) and deallocate_pages only frees it once the last one sharing it lets go
+// everything in here is physical addresses, the Page array and the pages are used through
+// the direct map and allocate_pages/deallocate_pages take and give direct map pointers
+// built with the alloc-tracking feature every allocation also remembers where it was made,
+// so anything still allocated (leaked) can be listed
+
+use crate::mmu::{phys_to_virt, virt_to_phys};
 use crate::println;
-use crate::HEAP_END;
-use crate::HEAP_SIZE;
+use crate::trap;
 use crate::HEAP_START;
 
+//physical address of the first allocatable page
 static mut ALLOC_START: usize = 12345;
+//end of usable ram, comes from the device tree so it isn't known at link time
+static mut HEAP_LIMIT: usize = 0;
+//physical start of the heap once init ran, firmware can reserve memory right after the kernel
+static mut HEAP_BASE: usize = 0;
 pub const PAGE_SIZE: usize = 4096;
 
+//largest block is 2^MAX_ORDER pages (4 GiB)
+const MAX_ORDER: usize = 20;
+const NO_PAGE: u32 = u32::MAX;
+const NOT_FREE_HEAD: u8 = u8::MAX;
+
 pub fn align(addr: usize, align_val: usize) -> usize {
     let new = addr + (align_val - (addr % align_val));
     assert!(new % align_val == 0);
@@ -18,11 +44,21 @@ enum PageBits {
     Empty = 0,
     Taken = 1 << 0,
     Last = 1 << 1,
+    //first page of a run the leak tracker had no room for
+    #[cfg(feature = "alloc-tracking")]
+    Untracked = 1 << 2,
 }
 
 #[derive(Clone, Copy)]
 struct Page {
     flags: u8,
+    //order of the free block starting at this page, NOT_FREE_HEAD for every other page
+    free_order: u8,
+    //free list links (page indexes), only used by free block heads
+    next: u32,
+    prev: u32,
+    //references to the run this page starts, 0 for every other page
+    refs: u16,
 }
 
 impl PageBits {
@@ -56,54 +92,403 @@ impl Page {
     }
 }
 
-pub fn allocate_pages(num_pages: usize) -> Result<*mut u8, &'static str> {
-    let total_pages: usize = unsafe { HEAP_SIZE } / PAGE_SIZE;
+//smallest order with 2^order >= num_pages
+fn order_for(num_pages: usize) -> usize {
+    num_pages.next_power_of_two().trailing_zeros() as usize
+}
 
-    let heap_start_page: *mut Page = unsafe { HEAP_START } as *mut Page;
+struct Buddy {
+    pages: *mut Page,
+    num_pages: usize,
+    free_lists: [u32; MAX_ORDER + 1],
+    used_pages: usize,
+    high_water_pages: usize,
+    //how many allocations have ever been made, also numbers them for leak tracking
+    allocations: u64,
+}
 
-    let mut found: bool = false;
+//only ever touched with the lock held
+unsafe impl Send for Buddy {}
+
+static BUDDY: spin::Mutex<Buddy> = spin::Mutex::new(Buddy {
+    pages: core::ptr::null_mut(),
+    num_pages: 0,
+    free_lists: [NO_PAGE; MAX_ORDER + 1],
+    used_pages: 0,
+    high_water_pages: 0,
+    allocations: 0,
+});
+
+impl Buddy {
+    fn page(&self, index: usize) -> &Page {
+        assert!(index < self.num_pages);
+        unsafe { &*self.pages.add(index) }
+    }
 
-    for start_page_index in 0..(total_pages - num_pages) {
-        let start: Page = unsafe { *heap_start_page.add(start_page_index) };
+    fn page_mut(&mut self, index: usize) -> &mut Page {
+        assert!(index < self.num_pages);
+        unsafe { &mut *self.pages.add(index) }
+    }
 
-        if start.is_free() {
-            found = true;
+    fn push(&mut self, index: usize, order: usize) {
+        let head: u32 = self.free_lists[order];
+        {
+            let page: &mut Page = self.page_mut(index);
+            assert!(page.is_free());
+            page.free_order = order as u8;
+            page.prev = NO_PAGE;
+            page.next = head;
+        }
+        if head != NO_PAGE {
+            self.page_mut(head as usize).prev = index as u32;
+        }
+        self.free_lists[order] = index as u32;
+    }
 
-            for next_page_index in start_page_index..(start_page_index + num_pages) {
-                unsafe {
-                    let curr: *mut Page = heap_start_page.add(next_page_index);
+    fn remove(&mut self, index: usize, order: usize) {
+        let (prev, next) = {
+            let page: &mut Page = self.page_mut(index);
+            assert!(page.free_order as usize == order);
+            page.free_order = NOT_FREE_HEAD;
+            (page.prev, page.next)
+        };
+        if prev == NO_PAGE {
+            self.free_lists[order] = next;
+        } else {
+            self.page_mut(prev as usize).next = next;
+        }
+        if next != NO_PAGE {
+            self.page_mut(next as usize).prev = prev;
+        }
+    }
 
-                    if (*curr).is_taken() {
-                        found = false;
-                        break;
-                    }
-                }
+    //the other half of the block index was split from, if it is a free block of the same order
+    fn free_buddy(&self, index: usize, order: usize) -> Option<usize> {
+        let buddy: usize = index ^ (1 << order);
+        if buddy + (1 << order) > self.num_pages {
+            return None;
+        }
+        if self.page(buddy).free_order as usize == order {
+            Some(buddy)
+        } else {
+            None
+        }
+    }
+
+    fn free_block(&mut self, mut index: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let Some(buddy) = self.free_buddy(index, order) else {
+                break;
+            };
+            self.remove(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push(index, order);
+    }
+
+    //free an arbitrary run of pages by splitting it into the largest aligned blocks
+    fn free_range(&mut self, start: usize, count: usize) {
+        let end: usize = start + count;
+        let mut index: usize = start;
+        while index < end {
+            let mut order: usize = (index.trailing_zeros() as usize).min(MAX_ORDER);
+            while index + (1 << order) > end {
+                order -= 1;
             }
+            self.free_block(index, order);
+            index += 1 << order;
+        }
+    }
+
+    fn allocate(&mut self, num_pages: usize) -> Option<usize> {
+        let order: usize = order_for(num_pages);
+        if order > MAX_ORDER {
+            return None;
         }
+        let mut found_order: usize = (order..=MAX_ORDER).find(|o| self.free_lists[*o] != NO_PAGE)?;
+        let start: usize = self.free_lists[found_order] as usize;
+        self.remove(start, found_order);
+
+        //split off the upper halves until the block is the right size
+        while found_order > order {
+            found_order -= 1;
+            self.push(start + (1 << found_order), found_order);
+        }
+        //and give back the tail we don't need
+        self.free_range(start + num_pages, (1 << order) - num_pages);
+
+        for index in start..(start + num_pages) {
+            let page: &mut Page = self.page_mut(index);
+            assert!(page.is_free());
+            page.mark_taken();
+        }
+        self.page_mut(start + num_pages - 1).mark_last();
+        self.page_mut(start).refs = 1;
+
+        self.used_pages += num_pages;
+        self.high_water_pages = self.high_water_pages.max(self.used_pages);
+        self.allocations += 1;
+        Some(start)
+    }
 
-        if found {
-            for next_page_index in start_page_index..(start_page_index + num_pages) {
-                unsafe {
-                    let curr: *mut Page = heap_start_page.add(next_page_index);
-                    assert!(!(*curr).is_taken());
-                    assert!(!(*curr).is_last());
-                    (*curr).mark_taken();
-                    assert!((*curr).is_taken());
+    fn share(&mut self, start: usize) {
+        let page: &mut Page = self.page_mut(start);
+        assert!(page.is_taken() && page.refs > 0);
+        page.refs = page.refs.checked_add(1).expect("page shared too many times");
+    }
+
+    //drop a reference to the run at start, true if that was the last one and it got freed
+    fn release(&mut self, start: usize) -> bool {
+        let page: &mut Page = self.page_mut(start);
+        assert!(page.refs > 0);
+        page.refs -= 1;
+        if page.refs > 0 {
+            return false;
+        }
+        self.deallocate(start);
+        true
+    }
+
+    fn deallocate(&mut self, start: usize) {
+        //has to be the first page of a run, not somewhere in the middle
+        assert!(start == 0 || !self.page(start - 1).is_taken() || self.page(start - 1).is_last());
+        assert!(self.page(start).refs == 0);
+
+        let mut index: usize = start;
+        loop {
+            let page: &mut Page = self.page_mut(index);
+            assert!(page.is_taken());
+            let last: bool = page.is_last();
+            page.clear();
+            index += 1;
+            if last {
+                break;
+            }
+        }
+        self.free_range(start, index - start);
+        self.used_pages -= index - start;
+    }
+
+    //longest run of free pages, which can be longer than any one free block
+    fn largest_free_run(&self) -> usize {
+        let mut largest: usize = 0;
+        let mut run: usize = 0;
+        for index in 0..self.num_pages {
+            if self.page(index).is_free() {
+                run += 1;
+                largest = largest.max(run);
+            } else {
+                run = 0;
+            }
+        }
+        largest
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AllocStats {
+    pub total_pages: usize,
+    pub free_pages: usize,
+    pub used_pages: usize,
+    //most pages that were ever in use at once
+    pub high_water_pages: usize,
+    pub largest_free_run: usize,
+    //0 when all free memory is one run, approaches 100 as it gets chopped up
+    pub fragmentation_percent: usize,
+    pub allocations: u64,
+}
+
+impl AllocStats {
+    pub fn print(&self) {
+        println!(
+            "pages: {} total, {} used, {} free, {} high water",
+            self.total_pages, self.used_pages, self.free_pages, self.high_water_pages
+        );
+        println!(
+            "largest free run {} pages, fragmentation {}%, {} allocations made",
+            self.largest_free_run, self.fragmentation_percent, self.allocations
+        );
+    }
+}
+
+pub fn stats() -> AllocStats {
+    trap::without_interrupts(|| {
+        let buddy = BUDDY.lock();
+        let free_pages: usize = buddy.num_pages - buddy.used_pages;
+        let largest_free_run: usize = buddy.largest_free_run();
+        AllocStats {
+            total_pages: buddy.num_pages,
+            free_pages,
+            used_pages: buddy.used_pages,
+            high_water_pages: buddy.high_water_pages,
+            largest_free_run,
+            fragmentation_percent: (100 * largest_free_run)
+                .checked_div(free_pages)
+                .map_or(0, |contiguous| 100 - contiguous),
+            allocations: buddy.allocations,
+        }
+    })
+}
+
+//below this many free pages (or 1/64 of them, whichever is more) memory is running low
+//and whoever can give pages back (see AddressSpace::reclaim) should
+const LOW_WATER_PAGES: usize = 64;
+
+pub fn running_low() -> bool {
+    trap::without_interrupts(|| {
+        let buddy = BUDDY.lock();
+        let low_water: usize = LOW_WATER_PAGES.max(buddy.num_pages / 64);
+        buddy.num_pages - buddy.used_pages < low_water
+    })
+}
+
+#[cfg(feature = "alloc-tracking")]
+mod tracking {
+    use core::panic::Location;
+
+    const MAX_TRACKED: usize = 1024;
+
+    #[derive(Clone, Copy, Debug)]
+    pub struct Allocation {
+        pub addr: usize,
+        pub num_pages: usize,
+        //which allocation this was, compare with AllocStats::allocations
+        pub number: u64,
+        pub caller: &'static Location<'static>,
+    }
+
+    pub struct Tracker {
+        live: [Option<Allocation>; MAX_TRACKED],
+        //allocations that didn't fit in the table
+        pub untracked: usize,
+    }
+
+    impl Tracker {
+        //false if the table is full and the allocation only got counted
+        pub fn insert(&mut self, allocation: Allocation) -> bool {
+            match self.live.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => {
+                    *slot = Some(allocation);
+                    true
+                }
+                None => {
+                    self.untracked += 1;
+                    false
                 }
             }
-            unsafe {
-                let last: *mut Page = heap_start_page.add(start_page_index + num_pages - 1);
-                assert!(!(*last).is_last());
-                assert!((*last).is_taken());
-                (*last).mark_last();
-                assert!((*last).is_last());
-            };
-            return Ok((unsafe { ALLOC_START } + (PAGE_SIZE * start_page_index)) as *mut u8);
         }
+
+        //untracked is what insert said about the allocation at addr
+        pub fn remove(&mut self, addr: usize, untracked: bool) {
+            if untracked {
+                self.untracked -= 1;
+                return;
+            }
+            if let Some(slot) = self
+                .live
+                .iter_mut()
+                .find(|slot| matches!(slot, Some(a) if a.addr == addr))
+            {
+                *slot = None;
+            }
+        }
+
+        pub fn live(&self) -> impl Iterator<Item = &Allocation> {
+            self.live.iter().flatten()
+        }
+    }
+
+    pub static TRACKER: spin::Mutex<Tracker> = spin::Mutex::new(Tracker {
+        live: [None; MAX_TRACKED],
+        untracked: 0,
+    });
+}
+
+#[cfg(feature = "alloc-tracking")]
+pub use tracking::Allocation;
+
+//call f on every allocation that hasn't been freed yet
+//f runs with the tracker locked so it must not allocate pages itself
+#[cfg(feature = "alloc-tracking")]
+pub fn for_each_live_allocation(f: impl FnMut(&Allocation)) {
+    trap::without_interrupts(|| tracking::TRACKER.lock().live().for_each(f));
+}
+
+//panic listing every allocation made after `before` was taken that is still around
+pub fn assert_no_leaks_since(before: &AllocStats) {
+    let now: AllocStats = stats();
+    if now.used_pages <= before.used_pages {
+        return;
+    }
+    println!(
+        "{} pages leaked since allocation {}",
+        now.used_pages - before.used_pages,
+        before.allocations
+    );
+    #[cfg(feature = "alloc-tracking")]
+    for_each_live_allocation(|allocation| {
+        if allocation.number >= before.allocations {
+            println!(
+                "  {:#010x} ({} pages) allocated at {}",
+                allocation.addr, allocation.num_pages, allocation.caller
+            );
+        }
+    });
+    panic!("page leak");
+}
+
+//physical address the heap starts at, right after the kernel image unless init was told otherwise
+pub fn heap_start() -> usize {
+    match unsafe { HEAP_BASE } {
+        0 => virt_to_phys(unsafe { HEAP_START }),
+        base => base,
+    }
+}
+
+fn heap_size() -> usize {
+    heap_end() - heap_start()
+}
+
+pub fn heap_end() -> usize {
+    unsafe { HEAP_LIMIT }
+}
+
+#[track_caller]
+pub fn allocate_pages(num_pages: usize) -> Result<*mut u8, &'static str> {
+    assert!(num_pages > 0);
+    //the kernel heap and the page fault handler allocate pages too, keep interrupts out of the lock
+    let (start, number) = trap::without_interrupts(|| {
+        let mut buddy = BUDDY.lock();
+        let start: usize = buddy.allocate(num_pages)?;
+        Some((start, buddy.allocations - 1))
+    })
+    .ok_or("unable to find contigious memory to allocate pages")?;
+    let addr: usize = phys_to_virt(unsafe { ALLOC_START } + (PAGE_SIZE * start));
+
+    #[cfg(feature = "alloc-tracking")]
+    {
+        let caller: &'static core::panic::Location<'static> = core::panic::Location::caller();
+        trap::without_interrupts(|| {
+            let tracked: bool = tracking::TRACKER.lock().insert(Allocation {
+                addr,
+                num_pages,
+                number,
+                caller,
+            });
+            //remembered in the page so freeing it knows to take it off the count
+            if !tracked {
+                BUDDY.lock().page_mut(start).flags |= PageBits::Untracked.byte();
+            }
+        });
     }
-    Err("unable to find contigious memory to allocate pages")
+    #[cfg(not(feature = "alloc-tracking"))]
+    let _ = number;
+
+    Ok(addr as *mut u8)
 }
 
+#[track_caller]
 pub fn zero_allocate_pages(num_pages: usize) -> Result<*mut u8, &'static str> {
     let memory: *mut u8 = allocate_pages(num_pages)?;
     let size: usize = num_pages * PAGE_SIZE;
@@ -117,28 +502,63 @@ pub fn zero_allocate_pages(num_pages: usize) -> Result<*mut u8, &'static str> {
     Ok(memory)
 }
 
-pub fn deallocate_pages(start_ptr: *mut u8) {
+//index of the page start_ptr (a pointer allocate_pages gave out) is
+fn page_index(start_ptr: *mut u8) -> usize {
     assert!(!start_ptr.is_null());
     assert!((start_ptr as usize) % PAGE_SIZE == 0);
-    let addr: usize =
-        unsafe { HEAP_START } + ((start_ptr as usize - unsafe { ALLOC_START }) / PAGE_SIZE);
-    assert!((unsafe { HEAP_START } <= addr) && (addr < unsafe { HEAP_END }));
-    let mut start_page: *mut Page = addr as *mut Page;
+    let start_addr: usize = virt_to_phys(start_ptr as usize);
+    assert!(start_addr >= unsafe { ALLOC_START });
+    (start_addr - unsafe { ALLOC_START }) / PAGE_SIZE
+}
 
-    unsafe {
-        while (*start_page).is_taken() && !(*start_page).is_last() {
-            (*start_page).clear();
-            start_page = start_page.add(1);
-        }
+//drop a reference to pages allocate_pages gave out, they are freed when it was the last one
+pub fn deallocate_pages(start_ptr: *mut u8) {
+    let start: usize = page_index(start_ptr);
+    //releasing the last reference clears the page's flags, so look before
+    let (freed, untracked): (bool, bool) = trap::without_interrupts(|| {
+        let mut buddy = BUDDY.lock();
+        #[cfg(feature = "alloc-tracking")]
+        let untracked: bool = buddy.page(start).flags & PageBits::Untracked.byte() != 0;
+        #[cfg(not(feature = "alloc-tracking"))]
+        let untracked: bool = false;
+        (buddy.release(start), untracked)
+    });
+
+    #[cfg(feature = "alloc-tracking")]
+    if freed {
+        trap::without_interrupts(|| {
+            tracking::TRACKER
+                .lock()
+                .remove(start_ptr as usize, untracked)
+        });
     }
-    assert!(unsafe { (*start_page).is_last() });
-    unsafe { (*start_page).clear() };
+    #[cfg(not(feature = "alloc-tracking"))]
+    let _ = (freed, untracked);
+}
+
+//take another reference to pages allocate_pages gave out, deallocate_pages has to be
+//called once more before they are really freed
+pub fn share_pages(start_ptr: *mut u8) {
+    let start: usize = page_index(start_ptr);
+    trap::without_interrupts(|| BUDDY.lock().share(start));
+}
+
+//how many references there are to the pages at start_ptr
+pub fn page_refs(start_ptr: *mut u8) -> usize {
+    let start: usize = page_index(start_ptr);
+    trap::without_interrupts(|| BUDDY.lock().page(start).refs as usize)
 }
 
 pub fn print_page_allocation() {
-    let total_pages = unsafe { HEAP_SIZE / PAGE_SIZE };
-    let page_data_begin = unsafe { HEAP_START };
-    let page_data_end = unsafe { (page_data_begin as *const Page).add(total_pages) } as usize;
+    //the heap and the page fault handler allocate from interrupts, keep them out of the lock
+    trap::without_interrupts(print_page_allocation_locked);
+}
+
+fn print_page_allocation_locked() {
+    let buddy = BUDDY.lock();
+    let total_pages = buddy.num_pages;
+    let page_data_begin = virt_to_phys(buddy.pages as usize);
+    let page_data_end = virt_to_phys(unsafe { buddy.pages.add(total_pages) } as usize);
 
     println!("page size   = {}", PAGE_SIZE);
     println!("total pages = {}", total_pages);
@@ -157,16 +577,15 @@ pub fn print_page_allocation() {
         unsafe { ALLOC_START } + total_pages * PAGE_SIZE
     );
 
-    let first_page: *mut Page = page_data_begin as *mut Page;
     let mut curr_in_page: bool = false;
     let mut start: usize = 0;
     let mut num_pages: u32 = 0;
     for page_index in 0..total_pages {
-        let curr: *mut Page = unsafe { first_page.add(page_index) };
+        let curr: &Page = buddy.page(page_index);
 
-        let curr_is_taken: bool = unsafe { *curr }.is_taken();
-        let curr_is_last: bool = unsafe { *curr }.is_last();
-        let curr_is_free: bool = unsafe { *curr }.is_free();
+        let curr_is_taken: bool = curr.is_taken();
+        let curr_is_last: bool = curr.is_last();
+        let curr_is_free: bool = curr.is_free();
 
         assert!(curr_is_taken ^ curr_is_free);
 
@@ -176,13 +595,13 @@ pub fn print_page_allocation() {
         } else if curr_is_taken {
             num_pages = 1;
             curr_in_page = true;
-            start = curr as usize;
+            start = unsafe { ALLOC_START } + page_index * PAGE_SIZE;
         }
         if curr_is_last {
             assert!(curr_in_page);
             assert!(curr_is_taken);
             curr_in_page = false;
-            let end = curr as usize;
+            let end = unsafe { ALLOC_START } + (page_index + 1) * PAGE_SIZE;
             println!(
                 "Page {:#010x} -> {:#010x} ({} pages)",
                 start, end, num_pages
@@ -190,23 +609,63 @@ pub fn print_page_allocation() {
         }
     }
     assert!(!curr_in_page);
+
+    for order in 0..=MAX_ORDER {
+        let mut count: usize = 0;
+        let mut index: u32 = buddy.free_lists[order];
+        while index != NO_PAGE {
+            count += 1;
+            index = buddy.page(index as usize).next;
+        }
+        if count != 0 {
+            println!("free blocks of {:>7} pages: {}", 1usize << order, count);
+        }
+    }
 }
 
-pub fn init() {
-    let num_pages: usize = unsafe { HEAP_SIZE } / PAGE_SIZE;
-    let first_page: *mut Page = unsafe { HEAP_START } as *mut Page;
+//heap_end is the physical address the heap stops at
+//the heap is [heap_start, heap_end), physical and page aligned, somewhere past the kernel image
+pub fn init(heap_start: usize, heap_end: usize) {
+    assert!(heap_start >= virt_to_phys(unsafe { HEAP_START }));
+    assert!(heap_start % PAGE_SIZE == 0);
+    assert!(heap_end > heap_start);
+    unsafe {
+        HEAP_BASE = heap_start;
+        HEAP_LIMIT = heap_end;
+    }
 
+    //every page costs PAGE_SIZE plus its descriptor, and aligning ALLOC_START can waste up to a page
+    let descriptor_size: usize = core::mem::size_of::<Page>();
+    let mut num_pages: usize = (heap_size() - PAGE_SIZE) / (PAGE_SIZE + descriptor_size);
+    assert!(num_pages < NO_PAGE as usize);
+    let alloc_start: usize = align(heap_start + num_pages * descriptor_size, PAGE_SIZE);
+    while alloc_start + num_pages * PAGE_SIZE > heap_end {
+        num_pages -= 1;
+    }
+    unsafe { ALLOC_START = alloc_start };
+
+    let first_page: *mut Page = phys_to_virt(heap_start) as *mut Page;
     for page_index in 0..num_pages {
         let page: *mut Page = unsafe { first_page.add(page_index) };
         unsafe {
-            (*page).clear();
+            page.write(Page {
+                flags: PageBits::Empty.byte(),
+                free_order: NOT_FREE_HEAD,
+                next: NO_PAGE,
+                prev: NO_PAGE,
+                refs: 0,
+            });
             assert!((*page).is_free());
             assert!(!(*page).is_taken());
             assert!(!(*page).is_last());
         }
     }
 
-    unsafe { ALLOC_START = HEAP_START + (num_pages * core::mem::size_of::<Page>()) };
-
-    unsafe { ALLOC_START = align(ALLOC_START, PAGE_SIZE) };
+    let mut buddy = BUDDY.lock();
+    buddy.pages = first_page;
+    buddy.num_pages = num_pages;
+    buddy.free_lists = [NO_PAGE; MAX_ORDER + 1];
+    buddy.used_pages = 0;
+    buddy.high_water_pages = 0;
+    buddy.free_range(0, num_pages);
 }
@@ -1,22 +1,138 @@
 use core::arch::asm;
-pub mod sv39;
+pub mod address_space;
+pub mod page_table;
+pub mod paging;
 
+//the kernel image is linked at its physical address + KERNEL_VIRT_OFFSET, which puts it in
+//the top 2 GiB of the address space (see linker.ld, entry.S has the same constants)
+pub const KERNEL_VIRT_OFFSET: usize = 0xffff_ffff_0000_0000;
+pub const KERNEL_VIRT_BASE: usize = 0xffff_ffff_8000_0000;
+
+//all of physical memory (and the mmio below it) is mapped at PHYS_OFFSET + physical address,
+//that is the start of the upper half in Sv39 so it is canonical in every paging mode
+//the kernel reaches page tables, page frames and devices through this direct map
+pub const PHYS_OFFSET: usize = 0xffff_ffc0_0000_0000;
+
+//kernel task stacks are mapped in here, between the kernel image and the top (see stack.rs)
+pub const KERNEL_STACKS_START: usize = 0xffff_ffff_a000_0000;
+pub const KERNEL_STACKS_END: usize = 0xffff_ffff_c000_0000;
+
+pub fn phys_to_virt(pa: usize) -> usize {
+    assert!(pa < KERNEL_VIRT_BASE - PHYS_OFFSET);
+    pa + PHYS_OFFSET
+}
+
+//only for addresses in the direct map, the kernel image or a kernel stack,
+//everything else needs a walk of the right page table
+pub fn virt_to_phys(va: usize) -> usize {
+    if (KERNEL_STACKS_START..KERNEL_STACKS_END).contains(&va) {
+        //every address space shares the kernel's half, so whatever table is active has the stacks
+        let root_pa: usize = (read_satp() & ((1 << 44) - 1)) << 12;
+        let root: &page_table::PageTable = unsafe { &*(phys_to_virt(root_pa) as *const _) };
+        return page_table::virt_to_phys(va, root).expect("kernel stack page not mapped") as usize;
+    }
+    if va >= KERNEL_VIRT_BASE {
+        va - KERNEL_VIRT_OFFSET
+    } else {
+        assert!(va >= PHYS_OFFSET);
+        va - PHYS_OFFSET
+    }
+}
+
+//map [start, end) of the kernel image or the direct map to where it is in physical memory
 pub fn memory_map_region(
     start: usize,
     end: usize,
-    root_table: &mut sv39::PageTable,
+    root_table: &mut page_table::PageTable,
+    protection_bits: usize,
+) {
+    map_region(
+        start,
+        virt_to_phys(start),
+        end - start,
+        root_table,
+        protection_bits,
+    );
+}
+
+//map len bytes at va to pa, rounded out to whole pages
+//uses the biggest page size that va and pa are both aligned to and that still fits
+//these are mappings that get used right away, so Accessed/Dirty start out set
+pub fn map_region(
+    va: usize,
+    pa: usize,
+    len: usize,
+    root_table: &mut page_table::PageTable,
     protection_bits: usize,
 ) {
-    assert!(protection_bits < (1 << 8));
-    assert!(start % 4096 == 0);
-    //assert!(end % 4096 == 0);
-    for addr in (start..end).step_by(4096) {
-        sv39::map(addr, addr, root_table, protection_bits).unwrap();
+    assert!(protection_bits < (1 << 10));
+    assert!(va % 4096 == 0);
+    assert!(pa % 4096 == 0);
+    let len: usize = len.div_ceil(4096) * 4096;
+
+    let mut offset: usize = 0;
+    while offset < len {
+        let size: page_table::PageSize = page_table::PageSize::all()
+            .find(|size| {
+                (va + offset) % size.bytes() == 0
+                    && (pa + offset) % size.bytes() == 0
+                    && offset + size.bytes() <= len
+            })
+            .unwrap();
+        page_table::map_page(
+            va + offset,
+            pa + offset,
+            root_table,
+            page_table::accessed_dirty(protection_bits),
+            size,
+        )
+        .unwrap();
+        offset += size.bytes();
     }
 }
 
-pub fn enable_mmu(root_table_ptr: *const sv39::PageTable) {
-    let root_table_ppn: usize = root_table_ptr as usize >> 12;
-    let satp_val: usize = (8 << 60) | root_table_ppn;
-    unsafe { asm!("csrw satp, {}", in(reg) satp_val) }
+//satp of the gigapage table entry.S turned paging on with, saved when we first switch away
+static BOOT_SATP: spin::Once<usize> = spin::Once::new();
+
+pub fn read_satp() -> usize {
+    let satp_val: usize;
+    unsafe { asm!("csrr {}, satp", out(reg) satp_val) };
+    satp_val
+}
+
+fn write_satp(satp_val: usize) {
+    BOOT_SATP.call_once(read_satp);
+    unsafe { asm!("csrw satp, {}", in(reg) satp_val) };
+}
+
+pub fn satp_for(root_table_ptr: *const page_table::PageTable, asid: usize) -> usize {
+    let root_table_ppn: usize = virt_to_phys(root_table_ptr as usize) >> 12;
+    (paging::active().satp_mode() << 60) | (asid << 44) | root_table_ppn
+}
+
+//switch to root_table, translations cached for asid before have to have been flushed already
+pub fn enable_mmu(root_table_ptr: *const page_table::PageTable, asid: usize) {
+    write_satp(satp_for(root_table_ptr, asid));
+}
+
+//go back to the boot page table, it maps the kernel and physical memory, so whatever
+//table we were on can be torn down after this
+pub fn use_boot_table() {
+    let boot_satp: usize = *BOOT_SATP.get().expect("still on the boot page table");
+    write_satp(boot_satp);
+    //the boot table has no asid, nothing cached for it was ever flushed
+    flush_tlb(None, None);
+}
+
+//sfence.vma, None for va means every address and None for asid means every address space
+//(including global mappings, which a flush of one asid leaves alone)
+pub fn flush_tlb(va: Option<usize>, asid: Option<usize>) {
+    unsafe {
+        match (va, asid) {
+            (Some(va), Some(asid)) => asm!("sfence.vma {}, {}", in(reg) va, in(reg) asid),
+            (Some(va), None) => asm!("sfence.vma {}, zero", in(reg) va),
+            (None, Some(asid)) => asm!("sfence.vma zero, {}", in(reg) asid),
+            (None, None) => asm!("sfence.vma zero, zero"),
+        }
+    }
 }
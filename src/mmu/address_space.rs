@@ -0,0 +1,655 @@
+// Address spaces
+// an AddressSpace owns a root page table, every table below it, the frames it allocated
+// itself for mappings (map_new) and an ASID, and gives all of them back when dropped
+// memory mapped with map (the kernel image, mmio, ...) belongs to someone else and is left alone
+// the ASID tags the TLB entries of the address space, so switching between address spaces
+// doesn't have to flush the whole TLB, a freshly allocated ASID is flushed once instead
+// ranges can also be reserved without backing them, the page fault handler gives each page
+// of a reserved region a zeroed frame the first time it is touched (demand paging)
+// fork clones an address space cheaply, the frames it owns are shared instead of copied and the
+// writable ones are mapped read only and marked copy on write in both, the first store to one
+// faults and gets its own copy (or the frame back, if nobody else is sharing it anymore)
+// without Svadu the hart faults on a clear Accessed/Dirty bit instead of setting it, the fault
+// handler sets them in software, which makes them usable for aging: a clock sweeps the owned
+// frames clearing Accessed, and pages that weren't touched since the last sweep are cold
+// cold pages of reserved regions that were never written are reclaimed when memory runs low,
+// they are only zeroes and the next touch faults a fresh zeroed frame in again
+// a process address space shares the kernel's upper half (share_kernel), its root points at the
+// kernel's tables there instead of having copies, so it only owns what is below
+// copy_from_user/copy_to_user are how the kernel gets at memory a user program handed it,
+// every page is looked up and checked against the page table first, the way the hart would
+
+use super::page_table::{self, Mapping, PageSize, PageTable, PteBits};
+use super::{flush_tlb, phys_to_virt, read_satp, virt_to_phys};
+use crate::memory_alloc;
+use crate::trap;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+//asid 0 belongs to the boot page table (and is the only one when the hart has no asids)
+const BOOT_ASID: usize = 0;
+//satp.ASID is bits 44-59
+const SATP_ASID_SHIFT: usize = 44;
+const SATP_ASID_MASK: usize = 0xffff;
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+//more address spaces than this at once would need asid recycling with generations
+const MAX_ASIDS: usize = 256;
+//how many pages a demand fault tries to reclaim when memory is running low
+const RECLAIM_BATCH: usize = 16;
+
+struct Asids {
+    used: [u64; MAX_ASIDS / 64],
+}
+
+static ASIDS: spin::Mutex<Asids> = spin::Mutex::new(Asids {
+    used: [1 << BOOT_ASID, 0, 0, 0],
+});
+
+//how many asids the hart has, found by writing all ones to satp.ASID and reading it back
+static ASID_COUNT: spin::Once<usize> = spin::Once::new();
+
+fn asid_count() -> usize {
+    *ASID_COUNT.call_once(|| {
+        let satp_val: usize = read_satp();
+        let all_ones: usize = satp_val | (SATP_ASID_MASK << SATP_ASID_SHIFT);
+        let read_back: usize;
+        //same table, just a different tag, nothing has been cached with the new asid
+        unsafe {
+            asm!(
+                "csrw satp, {all_ones}",
+                "csrr {out}, satp",
+                "csrw satp, {old}",
+                all_ones = in(reg) all_ones,
+                old = in(reg) satp_val,
+                out = out(reg) read_back,
+            );
+        }
+        let asid_bits: u32 = ((read_back >> SATP_ASID_SHIFT) & SATP_ASID_MASK).count_ones();
+        (1 << asid_bits).min(MAX_ASIDS)
+    })
+}
+
+fn asids_supported() -> bool {
+    asid_count() > 1
+}
+
+fn allocate_asid() -> Result<usize, &'static str> {
+    if !asids_supported() {
+        return Ok(BOOT_ASID);
+    }
+    let count: usize = asid_count();
+    let asid: usize = trap::without_interrupts(|| {
+        let mut asids = ASIDS.lock();
+        let asid: usize =
+            (0..count).find(|asid| asids.used[asid / 64] & (1 << (asid % 64)) == 0)?;
+        asids.used[asid / 64] |= 1 << (asid % 64);
+        Some(asid)
+    })
+    .ok_or("out of asids")?;
+    //whoever had it before may still have translations cached
+    flush_tlb(None, Some(asid));
+    Ok(asid)
+}
+
+fn release_asid(asid: usize) {
+    if asid == BOOT_ASID {
+        return;
+    }
+    trap::without_interrupts(|| ASIDS.lock().used[asid / 64] &= !(1 << (asid % 64)));
+}
+
+//what a faulting access was trying to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    fn allowed_by(&self, protection_bits: usize) -> bool {
+        let needed: PteBits = match self {
+            Access::Read => PteBits::Read,
+            Access::Write => PteBits::Write,
+            Access::Execute => PteBits::Execute,
+        };
+        protection_bits & needed.val() != 0
+    }
+}
+
+//a reserved range, its pages are mapped with protection_bits when first touched
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    end: usize,
+    protection_bits: usize,
+}
+
+pub struct AddressSpace {
+    root: *mut PageTable,
+    asid: usize,
+    //frames map_new and the page fault handler allocated, by the virtual address they are mapped at
+    frames: BTreeMap<usize, *mut u8>,
+    //reserved regions by start address, they don't overlap
+    regions: BTreeMap<usize, Region>,
+    //where the aging clock continues its sweep over frames
+    clock_hand: usize,
+    //the upper half is the kernel's tables, not ours to free
+    kernel_shared: bool,
+}
+
+//the address space activate() last switched to, for the page fault handler
+static ACTIVE: AtomicPtr<AddressSpace> = AtomicPtr::new(core::ptr::null_mut());
+
+//the tables and frames are only reachable through the AddressSpace
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace, &'static str> {
+        let asid: usize = allocate_asid()?;
+        let root: *mut PageTable = match memory_alloc::zero_allocate_pages(1) {
+            Ok(page) => page as *mut PageTable,
+            Err(error) => {
+                release_asid(asid);
+                return Err(error);
+            }
+        };
+        Ok(AddressSpace {
+            root,
+            asid,
+            frames: BTreeMap::new(),
+            regions: BTreeMap::new(),
+            clock_hand: 0,
+            kernel_shared: false,
+        })
+    }
+
+    pub fn asid(&self) -> usize {
+        self.asid
+    }
+
+    pub fn root(&self) -> &PageTable {
+        unsafe { &*self.root }
+    }
+
+    pub fn root_mut(&mut self) -> &mut PageTable {
+        unsafe { &mut *self.root }
+    }
+
+    pub fn satp(&self) -> usize {
+        super::satp_for(self.root, self.asid)
+    }
+
+    //is this the address space the hart is translating with
+    pub fn is_active(&self) -> bool {
+        read_satp() & SATP_PPN_MASK == self.satp() & SATP_PPN_MASK
+    }
+
+    //switch this hart to the address space
+    //it must not move while it is active, the page fault handler finds it by its address
+    pub fn activate(&mut self) {
+        ACTIVE.store(self as *mut AddressSpace, Ordering::Release);
+        super::enable_mmu(self.root, self.asid);
+        //without asids every address space is asid 0, so nothing cached can be trusted
+        if !asids_supported() {
+            flush_tlb(None, None);
+        }
+    }
+
+    //see the kernel's upper half through this address space too, kernel must outlive it
+    //kernel mappings made after this show up as long as they land under a root entry that
+    //already existed, so the kernel should have everything it needs mapped by then
+    pub fn share_kernel(&mut self, kernel: &AddressSpace) {
+        page_table::share_upper_half(self.root_mut(), kernel.root());
+        self.kernel_shared = true;
+    }
+
+    //copy bytes to va, which must be mapped, through the direct map
+    //so it works whether or not the address space is active and whatever the page protection
+    pub fn copy_to(&mut self, va: usize, bytes: &[u8]) -> Result<(), &'static str> {
+        let mut copied: usize = 0;
+        while copied < bytes.len() {
+            let page_offset: usize = (va + copied) % memory_alloc::PAGE_SIZE;
+            let chunk: usize = (memory_alloc::PAGE_SIZE - page_offset).min(bytes.len() - copied);
+            let pa: usize = self.translate(va + copied)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    bytes[copied..].as_ptr(),
+                    phys_to_virt(pa) as *mut u8,
+                    chunk,
+                )
+            };
+            copied += chunk;
+        }
+        Ok(())
+    }
+
+    //physical address of the user page va is in, checked for the U bit and access
+    //faults the page in (or copies it on write) the way a user access would, and sets
+    //Accessed/Dirty, the kernel goes through the direct map where the hart won't set them
+    fn user_page(&mut self, va: usize, access: Access) -> Result<usize, &'static str> {
+        if page_table::is_upper_half(va) {
+            return Err("not a user address");
+        }
+        let mut needed: usize = PteBits::UserMode.val() | PteBits::Accessed.val();
+        if access == Access::Write {
+            needed |= PteBits::Dirty.val();
+        }
+        let usable = |mapping: &Mapping| -> bool {
+            mapping.flags & needed == needed && access.allowed_by(mapping.flags)
+        };
+        let mapping: Option<Mapping> = page_table::leaf(self.root(), va);
+        if !mapping.as_ref().is_some_and(usable) {
+            self.handle_page_fault(va, access)?;
+        }
+        match page_table::leaf(self.root(), va) {
+            Some(mapping) if usable(&mapping) => Ok(mapping.pa + (va - mapping.va)),
+            _ => Err("user page not accessible"),
+        }
+    }
+
+    //fill buffer from user memory at va
+    pub fn copy_from_user(&mut self, va: usize, buffer: &mut [u8]) -> Result<(), &'static str> {
+        let mut copied: usize = 0;
+        while copied < buffer.len() {
+            let page_offset: usize = (va + copied) % memory_alloc::PAGE_SIZE;
+            let chunk: usize = (memory_alloc::PAGE_SIZE - page_offset).min(buffer.len() - copied);
+            let pa: usize = self.user_page(va + copied, Access::Read)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    phys_to_virt(pa) as *const u8,
+                    buffer[copied..].as_mut_ptr(),
+                    chunk,
+                )
+            };
+            copied += chunk;
+        }
+        Ok(())
+    }
+
+    //write bytes to user memory at va
+    pub fn copy_to_user(&mut self, va: usize, bytes: &[u8]) -> Result<(), &'static str> {
+        let mut copied: usize = 0;
+        while copied < bytes.len() {
+            let page_offset: usize = (va + copied) % memory_alloc::PAGE_SIZE;
+            let chunk: usize = (memory_alloc::PAGE_SIZE - page_offset).min(bytes.len() - copied);
+            let pa: usize = self.user_page(va + copied, Access::Write)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    bytes[copied..].as_ptr(),
+                    phys_to_virt(pa) as *mut u8,
+                    chunk,
+                )
+            };
+            copied += chunk;
+        }
+        Ok(())
+    }
+
+    //map memory the address space doesn't own, it is not freed on drop
+    pub fn map(&mut self, va: usize, pa: usize, len: usize, protection_bits: usize) {
+        super::map_region(va, pa, len, self.root_mut(), protection_bits);
+    }
+
+    //map len bytes of fresh zeroed frames at va, they are freed on unmap or drop
+    pub fn map_new(
+        &mut self,
+        va: usize,
+        len: usize,
+        protection_bits: usize,
+    ) -> Result<(), &'static str> {
+        if va % memory_alloc::PAGE_SIZE != 0 || len % memory_alloc::PAGE_SIZE != 0 {
+            return Err("range not page aligned");
+        }
+        for page_va in (va..va + len).step_by(memory_alloc::PAGE_SIZE) {
+            let frame: *mut u8 = memory_alloc::zero_allocate_pages(1)?;
+            let mapped: Result<(), &'static str> = page_table::map_page(
+                page_va,
+                virt_to_phys(frame as usize),
+                self.root_mut(),
+                page_table::accessed_dirty(protection_bits),
+                PageSize::Kilo,
+            );
+            if let Err(error) = mapped {
+                memory_alloc::deallocate_pages(frame);
+                return Err(error);
+            }
+            self.frames.insert(page_va, frame);
+        }
+        Ok(())
+    }
+
+    //remove the mappings in [va, va + len) and free the frames among them the address space owns
+    pub fn unmap(&mut self, va: usize, len: usize) -> Result<(), &'static str> {
+        let asid: usize = self.asid;
+        page_table::unmap_range(self.root_mut(), va, len, asid)?;
+        let mut unmapped: BTreeMap<usize, *mut u8> = self.frames.split_off(&va);
+        let mut after: BTreeMap<usize, *mut u8> = unmapped.split_off(&(va + len));
+        self.frames.append(&mut after);
+        for frame in unmapped.into_values() {
+            memory_alloc::deallocate_pages(frame);
+        }
+        Ok(())
+    }
+
+    pub fn protect(
+        &mut self,
+        va: usize,
+        len: usize,
+        protection_bits: usize,
+    ) -> Result<(), &'static str> {
+        let asid: usize = self.asid;
+        page_table::protect_range(self.root_mut(), va, len, protection_bits, asid)
+    }
+
+    //physical address va is mapped to
+    pub fn translate(&self, va: usize) -> Result<usize, &'static str> {
+        page_table::virt_to_phys(va, self.root()).map(|pa| pa as usize)
+    }
+
+    //how many frames map_new and the page fault handler handed out that are still mapped
+    pub fn owned_pages(&self) -> usize {
+        self.frames.len()
+    }
+
+    //reserve [va, va + len) without backing it, pages get a zeroed frame when first touched
+    pub fn reserve(
+        &mut self,
+        va: usize,
+        len: usize,
+        protection_bits: usize,
+    ) -> Result<(), &'static str> {
+        if va % memory_alloc::PAGE_SIZE != 0 || len % memory_alloc::PAGE_SIZE != 0 {
+            return Err("range not page aligned");
+        }
+        if len == 0 {
+            return Err("empty range");
+        }
+        let end: usize = va.checked_add(len).ok_or("range wraps around")?;
+        if let Some((_, before)) = self.regions.range(..end).next_back() {
+            if before.end > va {
+                return Err("range overlaps a reserved region");
+            }
+        }
+        self.regions.insert(
+            va,
+            Region {
+                end,
+                protection_bits,
+            },
+        );
+        Ok(())
+    }
+
+    //move the end of the region reserved at va, pages that end up outside of it are freed
+    pub fn resize(&mut self, va: usize, len: usize) -> Result<(), &'static str> {
+        if len % memory_alloc::PAGE_SIZE != 0 {
+            return Err("range not page aligned");
+        }
+        if len == 0 {
+            return Err("empty range");
+        }
+        let region: Region = *self.regions.get(&va).ok_or("no region reserved here")?;
+        let end: usize = va.checked_add(len).ok_or("range wraps around")?;
+        if end > region.end {
+            if let Some((&next, _)) = self.regions.range(va + 1..).next() {
+                if next < end {
+                    return Err("range overlaps a reserved region");
+                }
+            }
+        } else if end < region.end {
+            self.unmap(end, region.end - end)?;
+        }
+        self.regions.get_mut(&va).unwrap().end = end;
+        Ok(())
+    }
+
+    //give up the region reserved at va, along with every page of it that got touched
+    pub fn release(&mut self, va: usize) -> Result<(), &'static str> {
+        let region: Region = *self.regions.get(&va).ok_or("no region reserved here")?;
+        self.unmap(va, region.end - va)?;
+        self.regions.remove(&va);
+        Ok(())
+    }
+
+    fn region(&self, va: usize) -> Option<Region> {
+        let (_, region) = self.regions.range(..=va).next_back()?;
+        if va < region.end {
+            Some(*region)
+        } else {
+            None
+        }
+    }
+
+    //a new address space with the same mappings, frames this one owns become shared,
+    //writable ones copy on write, anything mapped with map is mapped the same in the clone
+    pub fn fork(&mut self) -> Result<AddressSpace, &'static str> {
+        let mut child: AddressSpace = AddressSpace::new()?;
+        child.regions = self.regions.clone();
+        if self.kernel_shared {
+            child.share_kernel(self);
+        }
+
+        let write: usize = PteBits::Write.val();
+        let copy_on_write: usize = PteBits::CopyOnWrite.val();
+        let leaves: alloc::vec::Vec<Mapping> = page_table::mappings(self.root()).collect();
+        for mapping in leaves {
+            if self.kernel_shared && page_table::is_upper_half(mapping.va) {
+                continue;
+            }
+            let mut flags: usize = mapping.flags;
+            if let Some(frame) = self.frames.get(&mapping.va).copied() {
+                if flags & write != 0 {
+                    flags = (flags & !write) | copy_on_write;
+                    let asid: usize = self.asid;
+                    page_table::remap(self.root_mut(), mapping.va, mapping.pa, flags, asid)?;
+                }
+                memory_alloc::share_pages(frame);
+                child.frames.insert(mapping.va, frame);
+            }
+            page_table::map_page(mapping.va, mapping.pa, child.root_mut(), flags, mapping.size)?;
+        }
+        Ok(child)
+    }
+
+    //a store to a copy on write page, give this address space a writable page of its own
+    fn break_copy_on_write(&mut self, mapping: Mapping) -> Result<(), &'static str> {
+        let asid: usize = self.asid;
+        let frame: *mut u8 = phys_to_virt(mapping.pa) as *mut u8;
+        let flags: usize = (mapping.flags & !PteBits::CopyOnWrite.val())
+            | PteBits::Write.val()
+            | PteBits::Accessed.val()
+            | PteBits::Dirty.val();
+
+        //everyone else already made their own copy, the frame is ours again
+        if memory_alloc::page_refs(frame) == 1 {
+            return page_table::remap(self.root_mut(), mapping.va, mapping.pa, flags, asid);
+        }
+
+        let copy: *mut u8 = memory_alloc::allocate_pages(1)?;
+        unsafe { core::ptr::copy_nonoverlapping(frame, copy, memory_alloc::PAGE_SIZE) };
+        let copy_pa: usize = virt_to_phys(copy as usize);
+        if let Err(error) = page_table::remap(self.root_mut(), mapping.va, copy_pa, flags, asid) {
+            memory_alloc::deallocate_pages(copy);
+            return Err(error);
+        }
+        self.frames.insert(mapping.va, copy);
+        memory_alloc::deallocate_pages(frame);
+        Ok(())
+    }
+
+    //back the page va is in with a zeroed frame, if it is in a reserved region that allows access
+    //or copy it, if it is a copy on write page being written to
+    pub fn handle_page_fault(&mut self, va: usize, access: Access) -> Result<(), &'static str> {
+        if let Some(mapping) = page_table::leaf(self.root(), va) {
+            let copy_on_write: bool = mapping.flags & PteBits::CopyOnWrite.val() != 0;
+            if access == Access::Write && copy_on_write {
+                assert!(mapping.size == PageSize::Kilo);
+                return self.break_copy_on_write(mapping);
+            }
+            //the mapping allows the access, the hart faulted on a clear Accessed/Dirty bit
+            let mut set: usize = PteBits::Accessed.val();
+            if access == Access::Write {
+                set |= PteBits::Dirty.val();
+            }
+            if access.allowed_by(mapping.flags) && mapping.flags & set != set {
+                let asid: usize = self.asid;
+                return page_table::update_flags(self.root_mut(), va, set, 0, asid).map(|_| ());
+            }
+            return Err("page is already mapped");
+        }
+
+        let region: Region = self.region(va).ok_or("address not in a reserved region")?;
+        if !access.allowed_by(region.protection_bits) {
+            return Err("access not allowed in this region");
+        }
+        let page_va: usize = va & !(memory_alloc::PAGE_SIZE - 1);
+
+        if memory_alloc::running_low() {
+            self.reclaim(RECLAIM_BATCH);
+        }
+        //only a store makes the page dirty, a page that was just read stays reclaimable
+        let mut bits: usize = region.protection_bits | PteBits::Accessed.val();
+        if access == Access::Write {
+            bits |= PteBits::Dirty.val();
+        }
+        let frame: *mut u8 = memory_alloc::zero_allocate_pages(1)?;
+        let mapped: Result<(), &'static str> = page_table::map_page(
+            page_va,
+            virt_to_phys(frame as usize),
+            self.root_mut(),
+            bits,
+            PageSize::Kilo,
+        );
+        if let Err(error) = mapped {
+            memory_alloc::deallocate_pages(frame);
+            return Err(error);
+        }
+        self.frames.insert(page_va, frame);
+        //the hart is allowed to have cached the invalid entry
+        flush_tlb(Some(page_va), Some(self.asid));
+        Ok(())
+    }
+
+    //clear Accessed on every owned frame, returns the addresses of the ones that had it set
+    pub fn harvest_accessed(&mut self) -> Vec<usize> {
+        let asid: usize = self.asid;
+        let pages: Vec<usize> = self.frames.keys().copied().collect();
+        let mut accessed: Vec<usize> = Vec::new();
+        for va in pages {
+            let clear: usize = PteBits::Accessed.val();
+            if let Ok(before) = page_table::update_flags(self.root_mut(), va, 0, clear, asid) {
+                if before & clear != 0 {
+                    accessed.push(va);
+                }
+            }
+        }
+        accessed
+    }
+
+    //one sweep of the clock over the owned frames, starting where the last one stopped
+    //a page with Accessed set gets it cleared and a second chance, one without is cold
+    //stops after finding wanted cold pages, returns their addresses
+    pub fn age(&mut self, wanted: usize) -> Vec<usize> {
+        let asid: usize = self.asid;
+        let hand: usize = self.clock_hand;
+        let pages: Vec<usize> = self
+            .frames
+            .range(hand..)
+            .chain(self.frames.range(..hand))
+            .map(|(va, _)| *va)
+            .collect();
+        let mut cold: Vec<usize> = Vec::new();
+        for va in pages {
+            if cold.len() == wanted {
+                break;
+            }
+            self.clock_hand = va + memory_alloc::PAGE_SIZE;
+            let clear: usize = PteBits::Accessed.val();
+            match page_table::update_flags(self.root_mut(), va, 0, clear, asid) {
+                Ok(before) if before & clear == 0 => cold.push(va),
+                _ => {}
+            }
+        }
+        cold
+    }
+
+    //give back up to wanted cold frames, returns how many were freed
+    //only pages of reserved regions that were never written and aren't shared qualify,
+    //their content is zeroes and touching them again faults in a fresh zeroed frame
+    pub fn reclaim(&mut self, wanted: usize) -> usize {
+        let asid: usize = self.asid;
+        let mut freed: usize = 0;
+        for va in self.age(wanted) {
+            let mapping: Mapping = match page_table::leaf(self.root(), va) {
+                Some(mapping) => mapping,
+                None => continue,
+            };
+            let pinned: usize = PteBits::Dirty.val() | PteBits::CopyOnWrite.val();
+            if mapping.flags & pinned != 0 || self.region(va).is_none() {
+                continue;
+            }
+            let frame: *mut u8 = self.frames[&va];
+            if memory_alloc::page_refs(frame) != 1 {
+                continue;
+            }
+            //the frame has to leave frames whenever its pte is gone, or the next fault's
+            //insert would overwrite it and leak it
+            let _ = page_table::unmap_range(self.root_mut(), va, memory_alloc::PAGE_SIZE, asid);
+            if page_table::leaf(self.root(), va).is_some() {
+                continue;
+            }
+            self.frames.remove(&va);
+            memory_alloc::deallocate_pages(frame);
+            freed += 1;
+        }
+        freed
+    }
+}
+
+//called by the trap handler for page faults, Err means it was a genuine fault
+//the kernel must not be in the middle of changing the active address space when it
+//touches memory that is only reserved
+//this makes a &mut out of the pointer activate() left behind, so while the kernel touches
+//reserved memory nothing may hold a reference to the active space, reach it through active()
+//instead and let every borrow end before the access (see test_demand_paging)
+pub fn handle_page_fault(va: usize, access: Access) -> Result<(), &'static str> {
+    let space: *mut AddressSpace = ACTIVE.load(Ordering::Acquire);
+    if space.is_null() {
+        return Err("no address space active");
+    }
+    let space: &mut AddressSpace = unsafe { &mut *space };
+    if !space.is_active() {
+        return Err("no address space active");
+    }
+    space.handle_page_fault(va, access)
+}
+
+//the address space activate() last switched to, null before the first one
+pub fn active() -> *mut AddressSpace {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        //tearing down the tables the hart is walking would pull the floor out from under us
+        assert!(!self.is_active(), "dropping the active address space");
+        let _ = ACTIVE.compare_exchange(
+            self as *mut AddressSpace,
+            core::ptr::null_mut(),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+        if self.kernel_shared {
+            page_table::forget_upper_half(self.root_mut());
+        }
+        page_table::unmap(self.root_mut());
+        memory_alloc::deallocate_pages(self.root as *mut u8);
+        for frame in core::mem::take(&mut self.frames).into_values() {
+            memory_alloc::deallocate_pages(frame);
+        }
+        flush_tlb(None, Some(self.asid));
+        release_asid(self.asid);
+    }
+}
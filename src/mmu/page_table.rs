@@ -0,0 +1,891 @@
+// Riscv Sv39/Sv48/Sv57 page table implementation
+// go to the riscv website and find the privileged ISA pdf (volume 2)
+// then there is a section explaining Sv39, Sv48 and Sv57 just add more levels
+// i don't really understand it that well ngl
+// everything here walks as many levels as paging::active() says
+// ptes hold physical addresses, the tables themselves are read and written through the direct map
+
+use super::paging;
+use super::phys_to_virt;
+use crate::memory_alloc;
+use crate::println;
+const PAGE_TABLE_NUM_ENTRIES: usize = 512;
+
+fn num_levels() -> usize {
+    paging::active().levels()
+}
+
+#[repr(usize)]
+#[derive(Copy, Clone)]
+pub enum PteBits {
+    Valid = 1 << 0,
+    Read = 1 << 1,
+    Write = 1 << 2,
+    Execute = 1 << 3,
+    UserMode = 1 << 4, // User Mode can access
+    Globe = 1 << 5,    // Global mapping
+    Accessed = 1 << 6,
+    Dirty = 1 << 7,
+    //the two RSW bits are left for software, the first marks a copy on write page
+    //(mapped read only, a store fault copies it, see AddressSpace::fork)
+    CopyOnWrite = 1 << 8,
+}
+
+//everything below the ppn, the hardware flags and the software (RSW) bits
+const PTE_FLAGS_LIMIT: usize = 1 << 10;
+
+impl PteBits {
+    pub fn val(&self) -> usize {
+        *self as usize
+    }
+}
+
+//Accessed, and Dirty too if the mapping is writable, to or into protection bits
+//without Svadu the hart doesn't set these itself, it faults when it finds them clear,
+//so mappings that are going to be used anyway start with them set
+pub fn accessed_dirty(protection_bits: usize) -> usize {
+    let mut bits: usize = protection_bits | PteBits::Accessed.val();
+    if protection_bits & PteBits::Write.val() != 0 {
+        bits |= PteBits::Dirty.val();
+    }
+    bits
+}
+
+//a leaf can sit at any level of the table, the higher up the bigger the page it maps
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    Kilo, //4 KiB, leaf in the last level table
+    Mega, //2 MiB megapage
+    Giga, //1 GiB gigapage
+    Tera, //512 GiB terapage, Sv48 and up
+    Peta, //256 TiB petapage, Sv57 only
+}
+
+impl PageSize {
+    pub fn bytes(&self) -> usize {
+        1 << (12 + 9 * self.depth())
+    }
+
+    fn depth(&self) -> usize {
+        match self {
+            PageSize::Kilo => 0,
+            PageSize::Mega => 1,
+            PageSize::Giga => 2,
+            PageSize::Tera => 3,
+            PageSize::Peta => 4,
+        }
+    }
+
+    //size of a leaf found in a table at depth
+    fn from_depth(depth: usize) -> PageSize {
+        match depth {
+            0 => PageSize::Kilo,
+            1 => PageSize::Mega,
+            2 => PageSize::Giga,
+            3 => PageSize::Tera,
+            4 => PageSize::Peta,
+            _ => panic!("no page size at depth {}", depth),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PageSize::Kilo => "4K",
+            PageSize::Mega => "2M",
+            PageSize::Giga => "1G",
+            PageSize::Tera => "512G",
+            PageSize::Peta => "256T",
+        }
+    }
+
+    //the sizes the active paging mode has, biggest first
+    pub fn all() -> impl Iterator<Item = PageSize> {
+        [
+            PageSize::Peta,
+            PageSize::Tera,
+            PageSize::Giga,
+            PageSize::Mega,
+            PageSize::Kilo,
+        ]
+        .into_iter()
+        .filter(|size| size.depth() < num_levels())
+    }
+}
+
+struct VirtAddr {
+    bits: usize,
+}
+
+struct Pte {
+    bits: usize,
+}
+
+pub struct PageTable {
+    entries: [Pte; PAGE_TABLE_NUM_ENTRIES],
+}
+
+impl VirtAddr {
+    fn get_vpn(&self, level: usize) -> usize {
+        paging::active().vpn(self.bits, level)
+    }
+
+    fn get_whole_vpn(&self) -> usize {
+        let vpn_bits: usize = paging::active().va_bits() - 12;
+        (self.bits >> 12) & ((1 << vpn_bits) - 1)
+    }
+
+    fn get_offset(&self) -> usize {
+        let twelve_ones: usize = 0xfff;
+
+        self.bits & twelve_ones
+    }
+}
+
+impl Pte {
+    fn new(ppn: usize, protection_bits: usize) -> Pte {
+        assert!(protection_bits < PTE_FLAGS_LIMIT);
+
+        let out = Pte {
+            bits: (ppn << 10) | protection_bits,
+        };
+        out.assert_not_reserved();
+        out
+    }
+
+    fn get_whole_ppn(&self) -> usize {
+        let fourty_four_ones: usize = 0xfff_ffff_ffff;
+        (self.bits >> 10) & fourty_four_ones
+    }
+
+    fn get_physical_addr(&self) -> *mut u8 {
+        let ppn: usize = self.get_whole_ppn();
+        (ppn << 12) as *mut u8
+    }
+
+    //where the table a branch points to can be reached
+    fn next_table(&self) -> *mut PageTable {
+        assert!(self.is_branch());
+        phys_to_virt(self.get_physical_addr() as usize) as *mut PageTable
+    }
+
+    fn is_valid(&self) -> bool {
+        self.bits & PteBits::Valid.val() != 0
+    }
+
+    fn is_read(&self) -> bool {
+        self.bits & PteBits::Read.val() != 0
+    }
+    fn is_write(&self) -> bool {
+        self.bits & PteBits::Write.val() != 0
+    }
+    fn is_execute(&self) -> bool {
+        self.bits & PteBits::Execute.val() != 0
+    }
+
+    fn is_branch(&self) -> bool {
+        !(self.is_read() || self.is_write() || self.is_execute())
+    }
+
+    fn is_leaf(&self) -> bool {
+        !self.is_branch()
+    }
+
+    fn is_reserved(&self) -> bool {
+        self.is_write() && !self.is_read()
+    }
+
+    fn assert_not_reserved(&self) {
+        assert!(!self.is_reserved());
+    }
+}
+
+fn virt_to_phys_rec(va: VirtAddr, root: &PageTable, depth: isize) -> Result<usize, &'static str> {
+    if depth < 0 {
+        return Err("depth reached negative before leaf found");
+    }
+    let curr_pte: &Pte = &root.entries[va.get_vpn(depth as usize)];
+
+    if !curr_pte.is_valid() {
+        return Err("hit invalid page");
+    }
+    curr_pte.assert_not_reserved();
+
+    if curr_pte.is_branch() {
+        let new_table: &PageTable = unsafe {
+            (curr_pte.next_table() as *const PageTable)
+                .as_ref()
+                .unwrap()
+        };
+        return virt_to_phys_rec(va, new_table, depth - 1);
+    }
+
+    assert!(curr_pte.is_leaf());
+
+    let vpn_mask: usize = (1 << (9 * depth)) - 1;
+
+    //only needed if doing more than 4kb pages, the low ppn fields of a superpage have to be zero
+    if curr_pte.get_whole_ppn() & vpn_mask != 0 {
+        return Err("misaligned superpage");
+    }
+
+    let ppn_bits = curr_pte.get_whole_ppn();
+    let vpn_bits = va.get_whole_vpn() & vpn_mask;
+    assert!(ppn_bits & vpn_bits == 0);
+    let page_number: usize = ppn_bits | vpn_bits;
+    let page_addr = page_number << 12;
+    let page_offset = va.get_offset();
+    assert!(page_addr & page_offset == 0);
+    Ok(page_addr | page_offset)
+}
+
+pub fn virt_to_phys(va: usize, root: &PageTable) -> Result<*mut u8, &'static str> {
+    if !paging::active().is_canonical(va) {
+        return Err("non canonical virtual address");
+    }
+    let va = VirtAddr { bits: va };
+
+    let out = virt_to_phys_rec(va, root, (num_levels() - 1) as isize)?;
+
+    Ok(out as *mut u8)
+}
+
+fn map_rec(
+    va: VirtAddr,
+    pa: usize,
+    root: &mut PageTable,
+    protection_bits: usize,
+    target_depth: usize,
+    curr_depth: isize,
+) -> Result<(), &'static str> {
+    assert!(curr_depth >= 0);
+    assert!((curr_depth as usize) >= target_depth);
+
+    let pte = &mut root.entries[va.get_vpn(curr_depth as usize)];
+
+    if (curr_depth as usize) == target_depth {
+        if pte.is_valid() {
+            return Err("double mapping page");
+        }
+        let fourty_four_ones: usize = 0xfff_ffff_ffff;
+        let ppn = (pa >> 12) & fourty_four_ones;
+        *pte = Pte::new(ppn, PteBits::Valid.val() | protection_bits);
+        return Ok(());
+    }
+
+    if pte.is_valid() && pte.is_leaf() {
+        return Err("address already mapped by a superpage");
+    }
+
+    if !pte.is_valid() {
+        let new_page: *mut u8 = memory_alloc::zero_allocate_pages(1).unwrap();
+        let new_entry = Pte::new(
+            super::virt_to_phys(new_page as usize) >> 12,
+            PteBits::Valid.val(),
+        );
+        *pte = new_entry;
+        assert!(core::ptr::eq(pte.next_table() as *mut u8, new_page));
+    }
+
+    let next_table = unsafe { pte.next_table().as_mut().unwrap() };
+
+    return map_rec(
+        va,
+        pa,
+        next_table,
+        protection_bits,
+        target_depth,
+        curr_depth - 1,
+    );
+}
+
+pub fn map(
+    va: usize,
+    pa: usize,
+    root: &mut PageTable,
+    protection_bits: usize,
+) -> Result<(), &'static str> {
+    map_page(va, pa, root, protection_bits, PageSize::Kilo)
+}
+
+//map one page of the given size, both addresses have to be aligned to it
+pub fn map_page(
+    va: usize,
+    pa: usize,
+    root: &mut PageTable,
+    protection_bits: usize,
+    size: PageSize,
+) -> Result<(), &'static str> {
+    if size.depth() >= num_levels() {
+        return Err("page size too big for paging mode");
+    }
+    if !paging::active().is_canonical(va) {
+        return Err("non canonical virtual address");
+    }
+    if va % size.bytes() != 0 || pa % size.bytes() != 0 {
+        return Err("address not aligned to page size");
+    }
+    map_rec(
+        VirtAddr { bits: va },
+        pa,
+        root,
+        protection_bits,
+        size.depth(),
+        (num_levels() - 1) as isize,
+    )
+}
+
+pub fn unmap_rec(root: &mut PageTable, depth: usize) {
+    assert!(depth < num_levels());
+
+    for i in 0..PAGE_TABLE_NUM_ENTRIES {
+        let pta = &mut root.entries[i];
+        if !pta.is_valid() {
+            continue;
+        }
+        if depth == 0 {
+            assert!(pta.is_leaf());
+        }
+        if pta.is_branch() {
+            let addr = pta.next_table() as *mut u8;
+            let next_table = unsafe { (addr as *mut PageTable).as_mut().unwrap() };
+            unmap_rec(next_table, depth - 1);
+            memory_alloc::deallocate_pages(addr);
+        }
+    }
+}
+
+pub fn unmap(root: &mut PageTable) {
+    unmap_rec(root, num_levels() - 1);
+}
+
+//root entries from here on map the upper half of the address space, where the kernel lives
+const UPPER_HALF_ENTRY: usize = PAGE_TABLE_NUM_ENTRIES / 2;
+
+pub fn is_upper_half(va: usize) -> bool {
+    (va as isize) < 0
+}
+
+//point the upper half of root at the tables the upper half of from uses
+//the tables stay from's, root just walks through them, see forget_upper_half
+pub fn share_upper_half(root: &mut PageTable, from: &PageTable) {
+    for i in UPPER_HALF_ENTRY..PAGE_TABLE_NUM_ENTRIES {
+        root.entries[i] = Pte {
+            bits: from.entries[i].bits,
+        };
+    }
+}
+
+//drop the shared upper half again, unmap would free tables that belong to someone else
+pub fn forget_upper_half(root: &mut PageTable) {
+    for pte in root.entries[UPPER_HALF_ENTRY..].iter_mut() {
+        pte.bits = 0;
+    }
+}
+
+//the bits of a pte below the ppn that a mapping's permissions live in (everything but Valid)
+const PROTECTION_MASK: usize = PTE_FLAGS_LIMIT - 2;
+
+//more pages than this and one flush of the whole asid is cheaper than flushing each page
+const MAX_SINGLE_PAGE_FLUSHES: usize = 64;
+
+//turn the index-derived address of a root table entry into a canonical virtual address
+fn sign_extend(va: usize) -> usize {
+    let unused_bits: usize = usize::BITS as usize - paging::active().va_bits();
+    (((va << unused_bits) as isize) >> unused_bits) as usize
+}
+
+fn check_range(va: usize, len: usize) -> Result<usize, &'static str> {
+    if va % 4096 != 0 || len % 4096 != 0 {
+        return Err("range not page aligned");
+    }
+    if len == 0 {
+        return Err("empty range");
+    }
+    let end: usize = va.checked_add(len).ok_or("range wraps around")?;
+    let mode = paging::active();
+    if !mode.is_canonical(va) || !mode.is_canonical(end - 1) {
+        return Err("non canonical virtual address");
+    }
+    //both ends in the same half, a range can't cross the hole in the middle
+    if (va as isize) < 0 && (end as isize) >= 0 {
+        return Err("range crosses the canonical hole");
+    }
+    if (va as isize) >= 0 && ((end - 1) as isize) < 0 {
+        return Err("range crosses the canonical hole");
+    }
+    Ok(end)
+}
+
+fn table_is_empty(table: &PageTable) -> bool {
+    table.entries.iter().all(|pte| !pte.is_valid())
+}
+
+//what walk_range_rec does with each leaf it finds
+enum LeafAction {
+    Unmap,
+    Protect(usize),
+}
+
+//the pages a walk changed, so the caller knows what to flush
+struct Touched {
+    pages: usize,
+    first_va: usize,
+    global: bool,
+    //a hole was skipped between changed pages, so they aren't all in first_va + pages
+    scattered: bool,
+    //sfence.vma with an address isn't guaranteed to drop cached entries of a freed table
+    freed_table: bool,
+}
+
+//apply action to every leaf in [start, end), the range can't cover only part of a superpage
+//protecting needs the whole range mapped, unmapping skips holes since a lazily backed
+//region (see AddressSpace::reserve) only has the pages that were touched
+//tables left empty by unmapping are freed and their entry cleared
+//without touched nothing is changed, the walk only finds out whether the action would fail
+fn walk_range_rec(
+    table: &mut PageTable,
+    depth: usize,
+    table_va: usize,
+    start: usize,
+    end: usize,
+    action: &LeafAction,
+    mut touched: Option<&mut Touched>,
+) -> Result<(), &'static str> {
+    let entry_size: usize = 1 << (12 + 9 * depth);
+    let first: usize = paging::active().vpn(start, depth);
+    let last: usize = paging::active().vpn(end - 1, depth);
+
+    for i in first..=last {
+        let entry_va: usize = if depth == num_levels() - 1 {
+            sign_extend(i * entry_size)
+        } else {
+            table_va + i * entry_size
+        };
+        let sub_start: usize = start.max(entry_va);
+        //end is exclusive, compare last bytes so the top entry of the address space can't wrap
+        let sub_end: usize = (end - 1).min(entry_va.wrapping_add(entry_size - 1)) + 1;
+        let pte: &mut Pte = &mut table.entries[i];
+
+        if !pte.is_valid() {
+            if matches!(action, LeafAction::Unmap) {
+                continue;
+            }
+            return Err("page not mapped");
+        }
+
+        if pte.is_leaf() {
+            if sub_start != entry_va || sub_end - sub_start != entry_size {
+                return Err("range covers part of a superpage");
+            }
+            let Some(touched) = touched.as_deref_mut() else {
+                continue;
+            };
+            if touched.pages == 0 {
+                touched.first_va = entry_va;
+            } else if entry_va != touched.first_va + touched.pages * 4096 {
+                touched.scattered = true;
+            }
+            touched.pages += entry_size / 4096;
+            touched.global |= pte.bits & PteBits::Globe.val() != 0;
+            match action {
+                LeafAction::Unmap => pte.bits = 0,
+                LeafAction::Protect(protection_bits) => {
+                    let new = Pte {
+                        bits: (pte.bits & !PROTECTION_MASK) | protection_bits,
+                    };
+                    new.assert_not_reserved();
+                    *pte = new;
+                }
+            }
+            continue;
+        }
+
+        assert!(depth > 0);
+        let next_addr: *mut u8 = pte.next_table() as *mut u8;
+        let next_table: &mut PageTable = unsafe { (next_addr as *mut PageTable).as_mut().unwrap() };
+        walk_range_rec(
+            next_table,
+            depth - 1,
+            entry_va,
+            sub_start,
+            sub_end,
+            action,
+            touched.as_deref_mut(),
+        )?;
+
+        if let Some(touched) = touched.as_deref_mut() {
+            if matches!(action, LeafAction::Unmap) && table_is_empty(next_table) {
+                table.entries[i].bits = 0;
+                memory_alloc::deallocate_pages(next_addr);
+                touched.freed_table = true;
+            }
+        }
+    }
+    Ok(())
+}
+
+//flush what a walk changed from the tlb, global mappings are cached for every asid
+fn flush_touched(touched: &Touched, asid: usize) {
+    let asid: Option<usize> = if touched.global { None } else { Some(asid) };
+    if touched.pages > MAX_SINGLE_PAGE_FLUSHES || touched.scattered || touched.freed_table {
+        super::flush_tlb(None, asid);
+        return;
+    }
+    for page in 0..touched.pages {
+        super::flush_tlb(Some(touched.first_va + page * 4096), asid);
+    }
+}
+
+fn walk_range(
+    root: &mut PageTable,
+    va: usize,
+    len: usize,
+    asid: usize,
+    action: LeafAction,
+) -> Result<(), &'static str> {
+    let end: usize = check_range(va, len)?;
+    let mut touched: Touched = Touched {
+        pages: 0,
+        first_va: va,
+        global: false,
+        scattered: false,
+        freed_table: false,
+    };
+    let depth: usize = num_levels() - 1;
+    //check the whole range first so an error leaves the table as it was
+    walk_range_rec(root, depth, 0, va, end, &action, None)?;
+    walk_range_rec(root, depth, 0, va, end, &action, Some(&mut touched))
+        .expect("range walk failed after checking it");
+    flush_touched(&touched, asid);
+    Ok(())
+}
+
+//remove every mapping in [va, va + len) and free page tables that end up empty
+//parts of the range that aren't mapped are skipped
+//the pages that were mapped are not freed, they belong to whoever mapped them
+pub fn unmap_range(
+    root: &mut PageTable,
+    va: usize,
+    len: usize,
+    asid: usize,
+) -> Result<(), &'static str> {
+    walk_range(root, va, len, asid, LeafAction::Unmap)
+}
+
+//change the permissions of every mapping in [va, va + len)
+pub fn protect_range(
+    root: &mut PageTable,
+    va: usize,
+    len: usize,
+    protection_bits: usize,
+    asid: usize,
+) -> Result<(), &'static str> {
+    check_leaf_bits(protection_bits)?;
+    walk_range(root, va, len, asid, LeafAction::Protect(protection_bits))
+}
+
+//bits a leaf can be given, without read, write or execute the pte would be a branch
+fn check_leaf_bits(protection_bits: usize) -> Result<(), &'static str> {
+    assert!(protection_bits < PTE_FLAGS_LIMIT);
+    if protection_bits & PteBits::Valid.val() != 0 {
+        return Err("protection bits include valid");
+    }
+    if protection_bits & (PteBits::Read.val() | PteBits::Write.val() | PteBits::Execute.val()) == 0
+    {
+        return Err("leaf needs at least one of read, write or execute, use unmap_range instead");
+    }
+    Ok(())
+}
+
+//point the page mapped at va somewhere else, with new permissions
+pub fn remap(
+    root: &mut PageTable,
+    va: usize,
+    new_pa: usize,
+    protection_bits: usize,
+    asid: usize,
+) -> Result<(), &'static str> {
+    check_leaf_bits(protection_bits)?;
+    if !paging::active().is_canonical(va) {
+        return Err("non canonical virtual address");
+    }
+    let va = VirtAddr { bits: va };
+    let mut table: &mut PageTable = root;
+    let mut depth: usize = num_levels() - 1;
+    loop {
+        let pte: &mut Pte = &mut table.entries[va.get_vpn(depth)];
+        if !pte.is_valid() {
+            return Err("page not mapped");
+        }
+        if pte.is_leaf() {
+            let page_size: usize = 1 << (12 + 9 * depth);
+            if new_pa % page_size != 0 {
+                return Err("address not aligned to page size");
+            }
+            let global: bool = pte.bits & PteBits::Globe.val() != 0;
+            let fourty_four_ones: usize = 0xfff_ffff_ffff;
+            *pte = Pte::new(
+                (new_pa >> 12) & fourty_four_ones,
+                PteBits::Valid.val() | protection_bits,
+            );
+            let page_va: usize = va.bits & !(page_size - 1);
+            super::flush_tlb(Some(page_va), if global { None } else { Some(asid) });
+            return Ok(());
+        }
+        if depth == 0 {
+            return Err("branch in last level table");
+        }
+        table = unsafe { pte.next_table().as_mut().unwrap() };
+        depth -= 1;
+    }
+}
+
+//deepest walk any paging mode has (Sv57)
+const MAX_LEVELS: usize = 5;
+
+//one leaf pte, flags are the pte's low bits without Valid
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    pub va: usize,
+    pub pa: usize,
+    pub size: PageSize,
+    pub flags: usize,
+}
+
+//walks every valid leaf of a table in virtual address order, lower half first
+//the tables must not change while this is around, it holds the root borrowed for that
+pub struct Mappings<'a> {
+    tables: [*const PageTable; MAX_LEVELS],
+    //next entry to look at in each table
+    indexes: [usize; MAX_LEVELS],
+    //virtual address the table at each depth starts mapping at
+    bases: [usize; MAX_LEVELS],
+    depth: usize,
+    _root: core::marker::PhantomData<&'a PageTable>,
+}
+
+pub fn mappings(root: &PageTable) -> Mappings<'_> {
+    let mut tables: [*const PageTable; MAX_LEVELS] = [core::ptr::null(); MAX_LEVELS];
+    tables[num_levels() - 1] = root;
+    Mappings {
+        tables,
+        indexes: [0; MAX_LEVELS],
+        bases: [0; MAX_LEVELS],
+        depth: num_levels() - 1,
+        _root: core::marker::PhantomData,
+    }
+}
+
+impl<'a> Iterator for Mappings<'a> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        loop {
+            let index: usize = self.indexes[self.depth];
+            if index == PAGE_TABLE_NUM_ENTRIES {
+                if self.depth == num_levels() - 1 {
+                    return None;
+                }
+                self.depth += 1;
+                continue;
+            }
+            self.indexes[self.depth] += 1;
+
+            let table: &PageTable = unsafe { &*self.tables[self.depth] };
+            let pte: &Pte = &table.entries[index];
+            if !pte.is_valid() {
+                continue;
+            }
+            let entry_size: usize = 1 << (12 + 9 * self.depth);
+            let va: usize = if self.depth == num_levels() - 1 {
+                sign_extend(index * entry_size)
+            } else {
+                self.bases[self.depth] + index * entry_size
+            };
+
+            if pte.is_leaf() {
+                return Some(Mapping {
+                    va,
+                    pa: pte.get_physical_addr() as usize,
+                    size: PageSize::from_depth(self.depth),
+                    flags: pte.bits & PROTECTION_MASK,
+                });
+            }
+
+            assert!(self.depth > 0);
+            self.depth -= 1;
+            self.tables[self.depth] = pte.next_table();
+            self.indexes[self.depth] = 0;
+            self.bases[self.depth] = va;
+        }
+    }
+}
+
+//pages that follow each other in both virtual and physical memory with the same size and flags
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Run {
+    pub va: usize,
+    pub pa: usize,
+    pub len: usize,
+    pub size: PageSize,
+    pub flags: usize,
+}
+
+impl Run {
+    fn continues_with(&self, mapping: &Mapping) -> bool {
+        mapping.va == self.va.wrapping_add(self.len)
+            && mapping.pa == self.pa + self.len
+            && mapping.size == self.size
+            && mapping.flags == self.flags
+    }
+}
+
+//like qemu's info mem, va range, pa, page size and flags
+impl core::fmt::Display for Run {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} {:#018x} {:>4} ",
+            self.va,
+            self.va.wrapping_add(self.len),
+            self.pa,
+            self.size.name()
+        )?;
+        let flag_names: [(PteBits, char); 8] = [
+            (PteBits::Read, 'r'),
+            (PteBits::Write, 'w'),
+            (PteBits::Execute, 'x'),
+            (PteBits::UserMode, 'u'),
+            (PteBits::Globe, 'g'),
+            (PteBits::Accessed, 'a'),
+            (PteBits::Dirty, 'd'),
+            (PteBits::CopyOnWrite, 'c'),
+        ];
+        for (bit, name) in flag_names {
+            let shown: char = if self.flags & bit.val() != 0 {
+                name
+            } else {
+                '-'
+            };
+            write!(f, "{}", shown)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Runs<'a> {
+    mappings: Mappings<'a>,
+    pending: Option<Mapping>,
+}
+
+pub fn runs(root: &PageTable) -> Runs<'_> {
+    Runs {
+        mappings: mappings(root),
+        pending: None,
+    }
+}
+
+impl<'a> Iterator for Runs<'a> {
+    type Item = Run;
+
+    fn next(&mut self) -> Option<Run> {
+        let first: Mapping = self.pending.take().or_else(|| self.mappings.next())?;
+        let mut run: Run = Run {
+            va: first.va,
+            pa: first.pa,
+            len: first.size.bytes(),
+            size: first.size,
+            flags: first.flags,
+        };
+        for mapping in self.mappings.by_ref() {
+            if !run.continues_with(&mapping) {
+                self.pending = Some(mapping);
+                break;
+            }
+            run.len += mapping.size.bytes();
+        }
+        Some(run)
+    }
+}
+
+//print every run of mappings in the table
+pub fn dump(root: &PageTable) {
+    println!(
+        "{:<18}-{:<18} {:<18} {:>4} flags",
+        "virtual start", "virtual end", "physical", "size"
+    );
+    for run in runs(root) {
+        println!("{}", run);
+    }
+}
+
+//the leaf va is mapped by, if there is one
+pub fn leaf(root: &PageTable, va: usize) -> Option<Mapping> {
+    if !paging::active().is_canonical(va) {
+        return None;
+    }
+    let mut table: &PageTable = root;
+    let mut depth: usize = num_levels() - 1;
+    loop {
+        let pte: &Pte = &table.entries[paging::active().vpn(va, depth)];
+        if !pte.is_valid() {
+            return None;
+        }
+        if pte.is_leaf() {
+            let size: PageSize = PageSize::from_depth(depth);
+            return Some(Mapping {
+                va: va & !(size.bytes() - 1),
+                pa: pte.get_physical_addr() as usize,
+                size,
+                flags: pte.bits & PROTECTION_MASK,
+            });
+        }
+        if depth == 0 {
+            return None;
+        }
+        table = unsafe { &*pte.next_table() };
+        depth -= 1;
+    }
+}
+
+//set and clear flag bits of the leaf mapping va, returns the flags it had before
+//flushes the page so the hart sees the change (and sets A/D again when it is used)
+pub fn update_flags(
+    root: &mut PageTable,
+    va: usize,
+    set: usize,
+    clear: usize,
+    asid: usize,
+) -> Result<usize, &'static str> {
+    assert!(set < PTE_FLAGS_LIMIT && clear < PTE_FLAGS_LIMIT);
+    assert!((set | clear) & PteBits::Valid.val() == 0);
+    if !paging::active().is_canonical(va) {
+        return Err("non canonical virtual address");
+    }
+    let mut table: &mut PageTable = root;
+    let mut depth: usize = num_levels() - 1;
+    loop {
+        let pte: &mut Pte = &mut table.entries[paging::active().vpn(va, depth)];
+        if !pte.is_valid() {
+            return Err("page not mapped");
+        }
+        if pte.is_leaf() {
+            let before: usize = pte.bits & PROTECTION_MASK;
+            let new = Pte {
+                bits: (pte.bits & !clear) | set,
+            };
+            new.assert_not_reserved();
+            assert!(new.is_leaf());
+            let global: bool = pte.bits & PteBits::Globe.val() != 0;
+            *pte = new;
+            let page_va: usize = va & !(PageSize::from_depth(depth).bytes() - 1);
+            super::flush_tlb(Some(page_va), if global { None } else { Some(asid) });
+            return Ok(before);
+        }
+        if depth == 0 {
+            return Err("branch in last level table");
+        }
+        table = unsafe { &mut *pte.next_table() };
+        depth -= 1;
+    }
+}
@@ -0,0 +1,162 @@
+// Paging modes
+// Sv39, Sv48 and Sv57 all use the same 8 byte PTEs, 512 entry tables and 9 bit VPN fields,
+// they only differ in how many levels the walk has (3, 4 or 5) and so how many bits
+// of virtual address get translated (39, 48 or 57)
+// the page table code is written against PagingMode and asks active() for the mode in use,
+// which probe() picks at boot from what the hart accepts in satp
+
+use super::virt_to_phys;
+use crate::memory_alloc;
+use core::arch::asm;
+
+pub trait PagingMode: Sync {
+    fn name(&self) -> &'static str;
+
+    fn levels(&self) -> usize;
+
+    //MODE field of satp
+    fn satp_mode(&self) -> usize;
+
+    fn va_bits(&self) -> usize {
+        12 + 9 * self.levels()
+    }
+
+    //index into the table at `level` (0 is the last level, levels() - 1 the root)
+    fn vpn(&self, va: usize, level: usize) -> usize {
+        assert!(level < self.levels());
+        (va >> (12 + 9 * level)) & 0x1ff
+    }
+
+    //all the bits above va_bits have to be copies of the top translated bit
+    fn is_canonical(&self, va: usize) -> bool {
+        let top: isize = (va as isize) >> (self.va_bits() - 1);
+        top == 0 || top == -1
+    }
+}
+
+pub struct Sv39;
+pub struct Sv48;
+pub struct Sv57;
+
+impl PagingMode for Sv39 {
+    fn name(&self) -> &'static str {
+        "Sv39"
+    }
+
+    fn levels(&self) -> usize {
+        3
+    }
+
+    fn satp_mode(&self) -> usize {
+        8
+    }
+}
+
+impl PagingMode for Sv48 {
+    fn name(&self) -> &'static str {
+        "Sv48"
+    }
+
+    fn levels(&self) -> usize {
+        4
+    }
+
+    fn satp_mode(&self) -> usize {
+        9
+    }
+}
+
+impl PagingMode for Sv57 {
+    fn name(&self) -> &'static str {
+        "Sv57"
+    }
+
+    fn levels(&self) -> usize {
+        5
+    }
+
+    fn satp_mode(&self) -> usize {
+        10
+    }
+}
+
+//biggest first, Sv39 is the smallest mode an rv64 hart with paging has to support
+static MODES: [&dyn PagingMode; 3] = [&Sv57, &Sv48, &Sv39];
+
+//tables from the root down to the gigapage level in the mode with the most levels
+const MAX_PROBE_TABLES: usize = 3;
+
+static ACTIVE: spin::Once<&'static dyn PagingMode> = spin::Once::new();
+
+//the mode page tables are built for, Sv39 (what the boot page table uses) until probe() has run
+//asking early doesn't pin it, only probe() sets it
+pub fn active() -> &'static dyn PagingMode {
+    ACTIVE.get().copied().unwrap_or(&Sv39)
+}
+
+//try turning on mode with a throwaway page table, satp ignores writes with a mode it
+//doesn't support, so reading it back tells us if the hart has it
+//the kernel is running from its linked address, so the table maps the gigapage the code
+//doing this is in (to the same memory the current table does), with one table per level
+//from the root down to the gigapage, so the instructions between switching to the table
+//and back can still be fetched
+fn supports(mode: &dyn PagingMode, tables: &[*mut usize; MAX_PROBE_TABLES]) -> bool {
+    let here: usize = supports as *const () as usize;
+    let giga_page_shift: usize = 30;
+    let giga_level: usize = 2;
+
+    //valid, read, write, execute, accessed, dirty
+    let leaf_bits: usize = 0b1100_1111;
+    let page_start: usize = (virt_to_phys(here) >> giga_page_shift) << giga_page_shift;
+    for level in (giga_level..mode.levels()).rev() {
+        let table_index: usize = mode.levels() - 1 - level;
+        let entry: usize = if level == giga_level {
+            ((page_start >> 12) << 10) | leaf_bits
+        } else {
+            let next_table: usize = virt_to_phys(tables[table_index + 1] as usize);
+            ((next_table >> 12) << 10) | 1
+        };
+        unsafe {
+            core::ptr::write_bytes(tables[table_index], 0, 512);
+            tables[table_index].add(mode.vpn(here, level)).write(entry);
+        }
+    }
+
+    let satp_val: usize = (mode.satp_mode() << 60) | (virt_to_phys(tables[0] as usize) >> 12);
+    let read_back: usize;
+    unsafe {
+        asm!(
+            "csrr {old}, satp",
+            "csrw satp, {satp}",
+            "sfence.vma zero, zero",
+            "csrr {out}, satp",
+            "csrw satp, {old}",
+            "sfence.vma zero, zero",
+            satp = in(reg) satp_val,
+            old = out(reg) _,
+            out = out(reg) read_back,
+        );
+    }
+    read_back == satp_val
+}
+
+//pick the biggest mode the hart supports, runs on the boot page table before kmain builds
+//the real one, interrupts have to be off since nothing but the kernel image is mapped while probing
+pub fn probe() -> &'static dyn PagingMode {
+    let mut tables: [*mut usize; MAX_PROBE_TABLES] = [core::ptr::null_mut(); MAX_PROBE_TABLES];
+    for table in tables.iter_mut() {
+        *table = memory_alloc::allocate_pages(1).unwrap() as *mut usize;
+    }
+    let mode: &'static dyn PagingMode = MODES
+        .iter()
+        .copied()
+        .find(|mode| supports(*mode, &tables))
+        .unwrap_or(&Sv39);
+    for table in tables {
+        memory_alloc::deallocate_pages(table as *mut u8);
+    }
+
+    //tables built for one mode and satp set to another would walk garbage
+    assert!(ACTIVE.get().is_none(), "paging mode already chosen");
+    *ACTIVE.call_once(|| mode)
+}
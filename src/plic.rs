@@ -0,0 +1,150 @@
+// Platform-Level Interrupt Controller (PLIC)
+// routes device interrupts (uart, virtio, ...) to harts
+// every source has a priority, every context (a hart in a privilege mode) has an
+// enable bit per source and a threshold, a source only interrupts a context
+// if it is enabled there and its priority is above the threshold
+// the context claims the interrupt to find out which source it was, then completes it
+
+use crate::mmu;
+use crate::trap;
+use crate::PLIC_ADDR;
+
+pub const PLIC_SIZE: usize = 0x400000;
+const PRIORITY_OFFSET: usize = 0x0;
+const ENABLE_OFFSET: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_OFFSET: usize = 0x200000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD_OFFSET: usize = 0x0;
+const CLAIM_OFFSET: usize = 0x4;
+
+//source 0 is reserved to mean "no interrupt"
+pub const MAX_IRQS: usize = 128;
+pub const MAX_PRIORITY: u32 = 7;
+
+//sie.SEIE
+const SIE_SEIE: usize = 1 << 9;
+
+//a hart in a privilege mode, on qemu virt each hart has an M mode and an S mode context
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Context(usize);
+
+impl Context {
+    pub fn supervisor(hart: usize) -> Context {
+        Context(2 * hart + 1)
+    }
+}
+
+fn reg(offset: usize) -> *mut u32 {
+    mmu::phys_to_virt(unsafe { PLIC_ADDR } + offset) as *mut u32
+}
+
+fn check_irq(irq: u32) -> Result<(), &'static str> {
+    if irq == 0 || irq as usize >= MAX_IRQS {
+        return Err("irq number out of range");
+    }
+    Ok(())
+}
+
+pub fn set_priority(irq: u32, priority: u32) -> Result<(), &'static str> {
+    check_irq(irq)?;
+    if priority > MAX_PRIORITY {
+        return Err("plic priority too high");
+    }
+    unsafe { reg(PRIORITY_OFFSET + 4 * irq as usize).write_volatile(priority) };
+    Ok(())
+}
+
+fn enable_word(context: Context, irq: u32) -> *mut u32 {
+    reg(ENABLE_OFFSET + ENABLE_STRIDE * context.0 + 4 * (irq as usize / 32))
+}
+
+pub fn enable(context: Context, irq: u32) -> Result<(), &'static str> {
+    check_irq(irq)?;
+    let word: *mut u32 = enable_word(context, irq);
+    unsafe { word.write_volatile(word.read_volatile() | (1 << (irq % 32))) };
+    Ok(())
+}
+
+pub fn disable(context: Context, irq: u32) -> Result<(), &'static str> {
+    check_irq(irq)?;
+    let word: *mut u32 = enable_word(context, irq);
+    unsafe { word.write_volatile(word.read_volatile() & !(1 << (irq % 32))) };
+    Ok(())
+}
+
+pub fn set_threshold(context: Context, threshold: u32) -> Result<(), &'static str> {
+    if threshold > MAX_PRIORITY {
+        return Err("plic threshold too high");
+    }
+    let threshold_ptr: *mut u32 =
+        reg(CONTEXT_OFFSET + CONTEXT_STRIDE * context.0 + THRESHOLD_OFFSET);
+    unsafe { threshold_ptr.write_volatile(threshold) };
+    Ok(())
+}
+
+//highest priority pending interrupt for this context, if any
+pub fn claim(context: Context) -> Option<u32> {
+    let claim_ptr: *mut u32 = reg(CONTEXT_OFFSET + CONTEXT_STRIDE * context.0 + CLAIM_OFFSET);
+    let irq: u32 = unsafe { claim_ptr.read_volatile() };
+    if irq == 0 {
+        None
+    } else {
+        Some(irq)
+    }
+}
+
+pub fn complete(context: Context, irq: u32) {
+    let claim_ptr: *mut u32 = reg(CONTEXT_OFFSET + CONTEXT_STRIDE * context.0 + CLAIM_OFFSET);
+    unsafe { claim_ptr.write_volatile(irq) };
+}
+
+type Handlers = [Option<fn()>; MAX_IRQS];
+
+static HANDLERS: spin::Mutex<Handlers> = spin::Mutex::new([None; MAX_IRQS]);
+
+//only the boot hart takes interrupts for now
+fn boot_context() -> Context {
+    Context::supervisor(crate::boot_hart())
+}
+
+//install handler for irq and unmask it on the boot hart
+//handlers run in interrupt context with interrupts off, keep them short
+pub fn register_handler(irq: u32, priority: u32, handler: fn()) -> Result<(), &'static str> {
+    check_irq(irq)?;
+    if priority == 0 {
+        return Err("priority 0 never interrupts");
+    }
+    trap::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        if handlers[irq as usize].is_some() {
+            return Err("irq already has a handler");
+        }
+        handlers[irq as usize] = Some(handler);
+        set_priority(irq, priority)?;
+        enable(boot_context(), irq)
+    })
+}
+
+//called from the trap handler on a supervisor external interrupt
+pub fn handle_interrupt() {
+    let context: Context = boot_context();
+    while let Some(irq) = claim(context) {
+        let handler: Option<fn()> = HANDLERS.lock()[irq as usize];
+        match handler {
+            Some(handler) => handler(),
+            None => crate::println!("plic: no handler for irq {}", irq),
+        }
+        complete(context, irq);
+    }
+}
+
+pub fn init() {
+    let context: Context = boot_context();
+    for irq in 1..(MAX_IRQS as u32) {
+        set_priority(irq, 0).unwrap();
+        disable(context, irq).unwrap();
+    }
+    set_threshold(context, 0).unwrap();
+    unsafe { core::arch::asm!("csrs sie, {}", in(reg) SIE_SEIE) }
+}
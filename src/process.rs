@@ -0,0 +1,208 @@
+// User processes
+// a process runs a program in user mode in an address space of its own, the lower half holds
+// the program and its stack (mapped with the U bit), the upper half is the kernel's, shared with
+// the kernel address space and mapped without U so user mode can't touch it
+// run() switches to the address space and enters user mode (enter_user in trap.S), interrupts
+// and page faults the kernel can fix are handled on the way and the program carries on,
+// so are system calls (see syscall.rs), run() only returns when the program yields, exits
+// or faulted and got killed for it
+// programs are either raw position independent code (new) or ELF executables (from_elf)
+
+use crate::elf;
+use crate::memory_alloc;
+use crate::mmu;
+use crate::mmu::address_space::{self, AddressSpace};
+use crate::mmu::page_table::PteBits;
+use crate::println;
+use crate::syscall::{self, Outcome};
+use crate::trap::{self, Cause, Exception, TrapFrame};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+//where programs are loaded, page 0 stays unmapped so null pointers fault
+pub const USER_TEXT: usize = 0x1_0000;
+//the stack grows down from here, it is reserved and only gets frames for what is used
+pub const USER_STACK_TOP: usize = 0x20_0000_0000;
+pub const USER_STACK_SIZE: usize = 1 << 20;
+//sbrk moves the break around in here, only the part below the break is reserved
+pub const USER_HEAP_START: usize = 0x1000_0000;
+pub const USER_HEAP_SIZE: usize = 1 << 30;
+
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Exited(usize),
+    //killed for a fault it couldn't recover from
+    Killed(Exception),
+}
+
+//why run() returned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    //gave up the hart for now, it can be run again
+    Yielded,
+    Exited(usize),
+    Killed(Exception),
+}
+
+pub struct Process {
+    pid: usize,
+    space: AddressSpace,
+    //boxed so it stays put, trap.S saves the registers into it through sscratch
+    frame: Box<TrapFrame>,
+    state: State,
+    //end of the heap as far as sbrk is concerned
+    brk: usize,
+}
+
+impl Process {
+    //a process running program (position independent code) from its first byte
+    //kernel is the address space whose upper half the process shares, it must outlive it
+    pub fn new(kernel: &AddressSpace, program: &[u8]) -> Result<Process, &'static str> {
+        let read: usize = PteBits::Read.val();
+        let write: usize = PteBits::Write.val();
+        let execute: usize = PteBits::Execute.val();
+        let user: usize = PteBits::UserMode.val();
+
+        let mut space: AddressSpace = AddressSpace::new()?;
+        space.share_kernel(kernel);
+        if !program.is_empty() {
+            let len: usize =
+                program.len().div_ceil(memory_alloc::PAGE_SIZE) * memory_alloc::PAGE_SIZE;
+            space.map_new(USER_TEXT, len, read | write | user)?;
+            space.copy_to(USER_TEXT, program)?;
+            space.protect(USER_TEXT, len, read | execute | user)?;
+        }
+        Process::with_space(space, USER_TEXT)
+    }
+
+    //a process running the ELF executable image from its entry point
+    pub fn from_elf(kernel: &AddressSpace, image: &[u8]) -> Result<Process, &'static str> {
+        let mut space: AddressSpace = AddressSpace::new()?;
+        space.share_kernel(kernel);
+        let entry: usize = elf::load(&mut space, image).map_err(|error| error.as_str())?;
+        Process::with_space(space, entry)
+    }
+
+    //give the program in space a stack, the heap starts out empty
+    fn with_space(mut space: AddressSpace, entry: usize) -> Result<Process, &'static str> {
+        let read: usize = PteBits::Read.val();
+        let write: usize = PteBits::Write.val();
+        let user: usize = PteBits::UserMode.val();
+        space.reserve(
+            USER_STACK_TOP - USER_STACK_SIZE,
+            USER_STACK_SIZE,
+            read | write | user,
+        )?;
+
+        Ok(Process {
+            pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+            space,
+            frame: Box::new(TrapFrame::new_user(entry, USER_STACK_TOP)),
+            state: State::Ready,
+            brk: USER_HEAP_START,
+        })
+    }
+
+    pub fn pid(&self) -> usize {
+        self.pid
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn space(&self) -> &AddressSpace {
+        &self.space
+    }
+
+    pub fn space_mut(&mut self) -> &mut AddressSpace {
+        &mut self.space
+    }
+
+    //the user registers, as of the last trap
+    pub fn frame(&self) -> &TrapFrame {
+        &self.frame
+    }
+
+    pub fn frame_mut(&mut self) -> &mut TrapFrame {
+        &mut self.frame
+    }
+
+    //move the break by increment bytes, returns where it was
+    //the heap region ends at the page the break is in, so anything above it faults for real
+    //pages that end up wholly above the break are given back, they read as zeroes if the
+    //break comes back up over them
+    pub fn sbrk(&mut self, increment: isize) -> Result<usize, &'static str> {
+        let old: usize = self.brk;
+        let new: usize = old
+            .checked_add_signed(increment)
+            .filter(|new| (USER_HEAP_START..=USER_HEAP_START + USER_HEAP_SIZE).contains(new))
+            .ok_or("break out of range")?;
+        let page = |addr: usize| addr.div_ceil(memory_alloc::PAGE_SIZE) * memory_alloc::PAGE_SIZE;
+        let (old_end, new_end): (usize, usize) = (page(old), page(new));
+        if new_end != old_end {
+            let bits: usize = PteBits::Read.val() | PteBits::Write.val() | PteBits::UserMode.val();
+            //an empty region can't be reserved, the heap only has one while it isn't empty
+            if old_end == USER_HEAP_START {
+                self.space
+                    .reserve(USER_HEAP_START, new_end - USER_HEAP_START, bits)?;
+            } else if new_end == USER_HEAP_START {
+                self.space.release(USER_HEAP_START)?;
+            } else {
+                self.space
+                    .resize(USER_HEAP_START, new_end - USER_HEAP_START)?;
+            }
+        }
+        self.brk = new;
+        Ok(old)
+    }
+
+    //run the program until it yields, exits or gets killed
+    //comes back in the address space that was active before
+    pub fn run(&mut self) -> Result<Exit, &'static str> {
+        if self.state != State::Ready {
+            return Err("process is not runnable");
+        }
+        let previous: *mut AddressSpace = address_space::active();
+        self.space.activate();
+        let exit: Exit = loop {
+            let frame: *mut TrapFrame = &mut *self.frame;
+            //user faults reach self.space through the pointer activate() stored, run doesn't
+            //touch it until enter_user returns and the fault handler's borrow is over
+            trap::without_interrupts(|| unsafe { trap::enter_user(frame) });
+
+            match self.frame.cause() {
+                Cause::Exception(Exception::UserEnvCall) => {
+                    //ecall is never compressed
+                    self.frame.sepc += 4;
+                    match syscall::dispatch(self) {
+                        Outcome::Return(_) => {}
+                        Outcome::Yield => break Exit::Yielded,
+                        Outcome::Exit(code) => {
+                            self.state = State::Exited(code);
+                            break Exit::Exited(code);
+                        }
+                    }
+                }
+                Cause::Exception(exception) => {
+                    println!("killing process {}", self.pid);
+                    trap::report_fault(&self.frame, exception);
+                    self.state = State::Killed(exception);
+                    break Exit::Killed(exception);
+                }
+                Cause::Interrupt(_) => {
+                    unreachable!("interrupts are handled without leaving user mode")
+                }
+            }
+        };
+        if previous.is_null() {
+            mmu::use_boot_table();
+        } else {
+            unsafe { (*previous).activate() };
+        }
+        Ok(exit)
+    }
+}
@@ -0,0 +1,231 @@
+// Supervisor Binary Interface (SBI) calls
+// when we boot as a payload of sbi firmware (opensbi), machine mode belongs to the firmware
+// and we ask it to do machine mode things (timers, ipis, starting harts, reset) with ecall
+// see the riscv sbi specification, every call puts the extension id in a7, the function id in a6,
+// arguments in a0-a5 and gets an error code back in a0 and a value in a1
+// without firmware the stub in entry.S answers every call with NotSupported
+
+use crate::mmu;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const EXT_BASE: usize = 0x10;
+const EXT_TIME: usize = 0x5449_4d45; //"TIME"
+const EXT_IPI: usize = 0x73_5049; //"sPI"
+const EXT_HSM: usize = 0x48_534d; //"HSM"
+const EXT_SRST: usize = 0x5352_5354; //"SRST"
+const EXT_DBCN: usize = 0x4442_434e; //"DBCN"
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    Unknown(isize),
+}
+
+impl SbiError {
+    fn from_code(code: isize) -> SbiError {
+        match code {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            _ => SbiError::Unknown(code),
+        }
+    }
+}
+
+fn ecall(
+    extension: usize,
+    function: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+) -> Result<usize, SbiError> {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") function,
+            in("a7") extension,
+        );
+    }
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(SbiError::from_code(error))
+    }
+}
+
+//base extension, always there if any firmware is
+
+pub fn get_spec_version() -> Result<(usize, usize), SbiError> {
+    let version: usize = ecall(EXT_BASE, 0, 0, 0, 0)?;
+    Ok(((version >> 24) & 0x7f, version & 0xff_ffff))
+}
+
+pub fn get_impl_id() -> Result<usize, SbiError> {
+    ecall(EXT_BASE, 1, 0, 0, 0)
+}
+
+pub fn get_impl_version() -> Result<usize, SbiError> {
+    ecall(EXT_BASE, 2, 0, 0, 0)
+}
+
+pub fn probe_extension(extension: usize) -> bool {
+    matches!(ecall(EXT_BASE, 3, extension, 0, 0), Ok(value) if value != 0)
+}
+
+//time extension
+
+//next supervisor timer interrupt at stime_value (in time csr ticks), also clears the pending one
+pub fn set_timer(stime_value: u64) -> Result<(), SbiError> {
+    ecall(EXT_TIME, 0, stime_value as usize, 0, 0).map(|_| ())
+}
+
+//ipi extension
+
+//raise a supervisor software interrupt on harts hart_mask_base + (set bits of hart_mask)
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+    ecall(EXT_IPI, 0, hart_mask, hart_mask_base, 0).map(|_| ())
+}
+
+//hart state management extension
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+    Unknown(usize),
+}
+
+//start hartid in supervisor mode at start_addr (physical) with a0 = hartid and a1 = opaque
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> Result<(), SbiError> {
+    ecall(EXT_HSM, 0, hartid, start_addr, opaque).map(|_| ())
+}
+
+//stop the calling hart, only returns on error
+pub fn hart_stop() -> SbiError {
+    match ecall(EXT_HSM, 1, 0, 0, 0) {
+        Ok(_) => SbiError::Failed,
+        Err(error) => error,
+    }
+}
+
+pub fn hart_get_status(hartid: usize) -> Result<HartState, SbiError> {
+    Ok(match ecall(EXT_HSM, 2, hartid, 0, 0)? {
+        0 => HartState::Started,
+        1 => HartState::Stopped,
+        2 => HartState::StartPending,
+        3 => HartState::StopPending,
+        4 => HartState::Suspended,
+        5 => HartState::SuspendPending,
+        6 => HartState::ResumePending,
+        other => HartState::Unknown(other),
+    })
+}
+
+//system reset extension
+
+#[repr(usize)]
+#[derive(Clone, Copy, Debug)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[repr(usize)]
+#[derive(Clone, Copy, Debug)]
+pub enum ResetReason {
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
+//only returns on error
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> SbiError {
+    match ecall(EXT_SRST, 0, reset_type as usize, reason as usize, 0) {
+        Ok(_) => SbiError::Failed,
+        Err(error) => error,
+    }
+}
+
+//debug console extension
+
+//write as much of bytes as the firmware takes, returns how many were written
+//the firmware doesn't use our page tables, it wants the physical address, and bytes are only
+//sure to be physically contiguous up to the end of the page (kernel stacks aren't)
+pub fn console_write(bytes: &[u8]) -> Result<usize, SbiError> {
+    let bytes_addr: usize = mmu::virt_to_phys(bytes.as_ptr() as usize);
+    let page_size: usize = crate::memory_alloc::PAGE_SIZE;
+    let len: usize = bytes.len().min(page_size - bytes_addr % page_size);
+    ecall(EXT_DBCN, 0, len, bytes_addr, 0)
+}
+
+//core::fmt::Write over the debug console, for println when the firmware owns the console
+pub struct SbiConsole;
+
+impl core::fmt::Write for SbiConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut bytes: &[u8] = s.as_bytes();
+        while !bytes.is_empty() {
+            let written: usize = console_write(bytes).map_err(|_| core::fmt::Error)?;
+            bytes = &bytes[written..];
+        }
+        Ok(())
+    }
+}
+
+//which extensions the firmware we booted under supports
+static PRESENT: AtomicBool = AtomicBool::new(false);
+static HAS_TIME: AtomicBool = AtomicBool::new(false);
+static HAS_SRST: AtomicBool = AtomicBool::new(false);
+static HAS_DBCN: AtomicBool = AtomicBool::new(false);
+
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+pub fn has_timer() -> bool {
+    HAS_TIME.load(Ordering::Relaxed)
+}
+
+pub fn has_reset() -> bool {
+    HAS_SRST.load(Ordering::Relaxed)
+}
+
+pub fn has_console() -> bool {
+    HAS_DBCN.load(Ordering::Relaxed)
+}
+
+//find out if there is firmware and what it can do, must run before anything uses the has_*() checks
+pub fn init() {
+    //probe_extension only exists from sbi 0.2 on
+    let present: bool = matches!(get_spec_version(), Ok((major, minor)) if major > 0 || minor >= 2);
+    PRESENT.store(present, Ordering::Relaxed);
+    if !present {
+        return;
+    }
+    HAS_TIME.store(probe_extension(EXT_TIME), Ordering::Relaxed);
+    HAS_SRST.store(probe_extension(EXT_SRST), Ordering::Relaxed);
+    HAS_DBCN.store(probe_extension(EXT_DBCN), Ordering::Relaxed);
+}
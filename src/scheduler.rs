@@ -0,0 +1,386 @@
+// Kernel tasks and a round robin scheduler
+// every task has a kernel stack of its own (with a guard page, see stack.rs) and a Context with
+// the registers switch_context (switch.S) saves, switching tasks is switching those
+// ready tasks wait in a queue and take turns, a periodic timer asks for a reschedule every
+// time slice and the trap handler does it on the way out of the interrupt (preempt), so a
+// task that never yields still has to give up the hart
+// when nothing is ready the idle task waits for interrupts
+// sleeping tasks are blocked with a deadline, a oneshot timer wakes them when it passes
+// what trap.S knows about the stack (see trap::KernelStack) and the active address space
+// belong to the task too, they are swapped along with the registers
+// a task that exits can't free the stack it is still running on, the next one does it
+
+use crate::clint;
+use crate::mmu::address_space::{self, AddressSpace};
+use crate::stack::Stack;
+use crate::trap;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+extern "C" {
+    fn switch_context(from: *mut Context, to: *const Context);
+    fn task_trampoline();
+}
+
+//what a task runs, boxed twice so task_trampoline can pass it around as one register
+type Entry = Box<dyn FnOnce() + Send + 'static>;
+
+//callee saved registers, layout must match switch.S
+#[repr(C)]
+#[derive(Default)]
+struct Context {
+    ra: usize,
+    sp: usize,
+    s: [usize; 12],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Running,
+    Ready,
+    //waiting for wake()
+    Blocked,
+    Dead,
+}
+
+struct Task {
+    id: usize,
+    name: &'static str,
+    context: Context,
+    //None for the boot stack kmain started on
+    stack: Option<Stack>,
+    kernel_stack: trap::KernelStack,
+    //the address space the task was running in when it was switched out
+    space: *mut AddressSpace,
+    state: State,
+}
+
+//tasks are only reached through the scheduler lock
+unsafe impl Send for Task {}
+
+struct Scheduler {
+    current: Option<Box<Task>>,
+    ready: VecDeque<Box<Task>>,
+    blocked: BTreeMap<usize, Box<Task>>,
+    //not in the ready queue, it only runs when nothing else can
+    idle: Option<Box<Task>>,
+    idle_id: usize,
+    //exited, waiting for the task that runs next to free its stack
+    dead: Option<Box<Task>>,
+    timer: Option<clint::TimerId>,
+    //blocked tasks waiting for a deadline, by id
+    sleepers: Vec<(clint::Instant, usize)>,
+}
+
+static SCHEDULER: spin::Mutex<Scheduler> = spin::Mutex::new(Scheduler {
+    current: None,
+    ready: VecDeque::new(),
+    blocked: BTreeMap::new(),
+    idle: None,
+    idle_id: 0,
+    dead: None,
+    timer: None,
+    sleepers: Vec::new(),
+});
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+//set by the time slice timer and wake(), the trap handler reschedules when it sees it
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+fn new_task(name: &'static str, entry: Entry) -> Result<Box<Task>, &'static str> {
+    let stack: Stack = Stack::new()?;
+    let mut s: [usize; 12] = [0; 12];
+    //task_trampoline hands s0 to task_start, which takes the entry back
+    s[0] = Box::into_raw(Box::new(entry)) as usize;
+    let context: Context = Context {
+        ra: task_trampoline as *const () as usize,
+        sp: stack.top(),
+        s,
+    };
+    Ok(Box::new(Task {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        name,
+        context,
+        kernel_stack: trap::KernelStack {
+            limit: stack.bottom(),
+            user_trap_sp: 0,
+        },
+        stack: Some(stack),
+        space: address_space::active(),
+        state: State::Ready,
+    }))
+}
+
+//where a new task starts, on its own stack, called from task_trampoline in switch.S
+#[no_mangle]
+extern "C" fn task_start(entry: usize) -> ! {
+    //new_task put a boxed Entry there
+    let entry: Box<Entry> = unsafe { Box::from_raw(entry as *mut Entry) };
+    finish_switch();
+    //schedule() switched to us with interrupts off
+    trap::enable_interrupts();
+    entry();
+    exit();
+}
+
+fn idle_loop() {
+    loop {
+        unsafe { core::arch::asm!("wfi") }
+    }
+}
+
+//turn what is running now (kmain on the boot stack) into the first task and make the idle task
+pub fn init() -> Result<(), &'static str> {
+    let idle: Box<Task> = new_task("idle", Box::new(idle_loop))?;
+    let boot: Box<Task> = Box::new(Task {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        name: "main",
+        context: Context::default(),
+        stack: None,
+        kernel_stack: trap::kernel_stack(),
+        space: address_space::active(),
+        state: State::Running,
+    });
+    trap::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if scheduler.current.is_some() {
+            return Err("scheduler already initialized");
+        }
+        scheduler.idle_id = idle.id;
+        scheduler.idle = Some(idle);
+        scheduler.current = Some(boot);
+        Ok(())
+    })
+}
+
+//start preempting, every task gets at most time_slice before the next one's turn
+pub fn start(time_slice: Duration) -> Result<(), &'static str> {
+    let timer: clint::TimerId = clint::set_periodic(time_slice, tick)?;
+    let previous: Option<clint::TimerId> =
+        trap::without_interrupts(|| SCHEDULER.lock().timer.replace(timer));
+    if let Some(previous) = previous {
+        clint::cancel(previous);
+    }
+    Ok(())
+}
+
+//undo init, only the task that called it may be left
+pub fn stop() {
+    let (timer, idle, boot, ready, sleepers) = trap::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        assert!(scheduler.ready.is_empty() && scheduler.blocked.is_empty());
+        assert!(scheduler
+            .current
+            .as_ref()
+            .is_some_and(|task| task.stack.is_none()));
+        (
+            scheduler.timer.take(),
+            scheduler.idle.take(),
+            scheduler.current.take(),
+            //the queue and the list hold on to their buffers even when empty
+            core::mem::take(&mut scheduler.ready),
+            core::mem::take(&mut scheduler.sleepers),
+        )
+    });
+    if let Some(timer) = timer {
+        clint::cancel(timer);
+    }
+    drop(idle);
+    drop(boot);
+    drop(ready);
+    drop(sleepers);
+}
+
+//tasks other than the idle task, whatever state they are in
+pub fn task_count() -> usize {
+    trap::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let current: usize = match &scheduler.current {
+            Some(task) if task.id != scheduler.idle_id => 1,
+            _ => 0,
+        };
+        current + scheduler.ready.len() + scheduler.blocked.len()
+    })
+}
+
+pub fn is_running() -> bool {
+    trap::without_interrupts(|| SCHEDULER.lock().current.is_some())
+}
+
+fn tick() {
+    NEED_RESCHED.store(true, Ordering::Relaxed);
+    //in case a sleeper's own timer couldn't be set up
+    wake_sleepers();
+}
+
+//add a task running entry, it exits when entry returns
+pub fn spawn(
+    name: &'static str,
+    entry: impl FnOnce() + Send + 'static,
+) -> Result<usize, &'static str> {
+    let task: Box<Task> = new_task(name, Box::new(entry))?;
+    let id: usize = task.id;
+    trap::without_interrupts(|| SCHEDULER.lock().ready.push_back(task));
+    Ok(id)
+}
+
+pub fn current_id() -> usize {
+    trap::without_interrupts(|| SCHEDULER.lock().current.as_ref().map_or(0, |task| task.id))
+}
+
+pub fn current_name() -> &'static str {
+    trap::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .current
+            .as_ref()
+            .map_or("none", |task| task.name)
+    })
+}
+
+//let the next ready task run
+pub fn yield_now() {
+    trap::without_interrupts(schedule);
+}
+
+//called by the trap handler on the way out of an interrupt
+pub fn preempt() {
+    if NEED_RESCHED.swap(false, Ordering::Relaxed) {
+        schedule();
+    }
+}
+
+//take the current task off the hart until someone calls wake() with its id
+//interrupts have to be off from checking whatever it waits for until here,
+//or the wakeup could come in between and get lost
+pub fn block() {
+    assert!(!trap::interrupts_enabled());
+    set_current_state(State::Blocked);
+    schedule();
+}
+
+//block the current task for at least duration
+pub fn sleep(duration: Duration) {
+    let deadline: clint::Instant = clint::Instant::now() + duration;
+    trap::without_interrupts(|| loop {
+        {
+            let mut scheduler = SCHEDULER.lock();
+            let id: usize = scheduler.current.as_ref().expect("no current task").id;
+            //someone else's wake() can get us going early, a stale entry would later wake us
+            //out of whatever we block on next
+            scheduler.sleepers.retain(|&(_, sleeper)| sleeper != id);
+            if clint::Instant::now() >= deadline {
+                return;
+            }
+            scheduler.sleepers.push((deadline, id));
+        }
+        //out of timers the time slice tick still gets to it, just later
+        let _ = clint::set_oneshot(
+            deadline.duration_since(clint::Instant::now()),
+            wake_sleepers,
+        );
+        block();
+    });
+}
+
+//wake the sleepers whose deadline has passed
+fn wake_sleepers() {
+    let now: clint::Instant = clint::Instant::now();
+    let mut due: Vec<usize> = Vec::new();
+    trap::without_interrupts(|| {
+        SCHEDULER.lock().sleepers.retain(|&(deadline, id)| {
+            if deadline <= now {
+                due.push(id);
+            }
+            deadline > now
+        });
+    });
+    for id in due {
+        wake(id);
+    }
+}
+
+//make a blocked task ready again, does nothing if it isn't blocked
+pub fn wake(id: usize) {
+    trap::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(mut task) = scheduler.blocked.remove(&id) {
+            task.state = State::Ready;
+            scheduler.ready.push_back(task);
+            NEED_RESCHED.store(true, Ordering::Relaxed);
+        }
+    });
+}
+
+//end the current task
+pub fn exit() -> ! {
+    trap::disable_interrupts();
+    set_current_state(State::Dead);
+    schedule();
+    unreachable!("a dead task got scheduled");
+}
+
+fn set_current_state(state: State) {
+    let mut scheduler = SCHEDULER.lock();
+    let current: &mut Task = scheduler.current.as_mut().expect("no current task");
+    current.state = state;
+}
+
+//switch to the next ready task, or the idle task if the current one can't go on
+//interrupts must be off
+fn schedule() {
+    let (from, to): (*mut Context, *const Context) = {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(mut current) = scheduler.current.take() else {
+            return;
+        };
+        let mut next: Box<Task> = match scheduler.ready.pop_front() {
+            Some(next) => next,
+            None if current.state == State::Running => {
+                scheduler.current = Some(current);
+                return;
+            }
+            None => scheduler.idle.take().expect("idle task can't go on"),
+        };
+
+        current.kernel_stack = trap::kernel_stack();
+        current.space = address_space::active();
+        //the box keeps the task in place while it moves between the lists
+        let from: *mut Context = &mut current.context;
+        match current.state {
+            State::Running if current.id == scheduler.idle_id => scheduler.idle = Some(current),
+            State::Running => {
+                current.state = State::Ready;
+                scheduler.ready.push_back(current);
+            }
+            State::Blocked => {
+                scheduler.blocked.insert(current.id, current);
+            }
+            State::Dead => {
+                assert!(scheduler.dead.is_none());
+                scheduler.dead = Some(current);
+            }
+            State::Ready => unreachable!("the current task is never just ready"),
+        }
+
+        next.state = State::Running;
+        trap::set_kernel_stack(next.kernel_stack);
+        if !next.space.is_null() && unsafe { !(*next.space).is_active() } {
+            unsafe { (*next.space).activate() };
+        }
+        let to: *const Context = &next.context;
+        scheduler.current = Some(next);
+        (from, to)
+    };
+    unsafe { switch_context(from, to) };
+    finish_switch();
+}
+
+//runs right after every switch, in the task that was switched to
+fn finish_switch() {
+    let dead: Option<Box<Task>> = SCHEDULER.lock().dead.take();
+    drop(dead);
+}
@@ -0,0 +1,143 @@
+// Kernel task stacks
+// every task runs on a stack of its own, frames from memory_alloc mapped into
+// [KERNEL_STACKS_START, KERNEL_STACKS_END) with an unmapped guard page below each one,
+// so running off the bottom faults (and trap.S notices, see kernel_stack_limit) instead of
+// quietly writing over whatever sits next to the frames in the direct map
+// the area is in the kernel's half of its address space, which every process shares, so the
+// stacks are mapped global and an unmapped one is flushed out of every address space's TLB
+// slots are handed out from the bottom of the area and reused once freed
+
+use crate::memory_alloc::{self, PAGE_SIZE};
+use crate::mmu::address_space::AddressSpace;
+use crate::mmu::page_table::{self, PageSize, PageTable, PteBits};
+use crate::mmu::{self, KERNEL_STACKS_END, KERNEL_STACKS_START};
+use crate::trap;
+use alloc::vec::Vec;
+
+pub const STACK_PAGES: usize = 8;
+//the guard page and the stack above it
+const SLOT_SIZE: usize = (STACK_PAGES + 1) * PAGE_SIZE;
+
+struct Slots {
+    //the kernel address space's root table, processes share the entry covering the area
+    root: *mut PageTable,
+    asid: usize,
+    //slots below this have been handed out at some point
+    next: usize,
+    free: Vec<usize>,
+}
+
+//only reached through the lock
+unsafe impl Send for Slots {}
+
+static SLOTS: spin::Mutex<Option<Slots>> = spin::Mutex::new(None);
+
+//map stacks into kernel from now on, it must outlive every stack
+//the area shares its root entry with the kernel image, which kernel already maps,
+//so processes that share the kernel's half see stacks mapped after they were made too
+pub fn init(kernel: &mut AddressSpace) {
+    let asid: usize = kernel.asid();
+    let root: *mut PageTable = kernel.root_mut();
+    trap::without_interrupts(|| {
+        *SLOTS.lock() = Some(Slots {
+            root,
+            asid,
+            next: 0,
+            free: Vec::new(),
+        });
+    });
+}
+
+//stop handing out stacks, every stack must be gone already
+pub fn deinit() {
+    let slots: Option<Slots> = trap::without_interrupts(|| SLOTS.lock().take());
+    if let Some(slots) = slots {
+        assert_eq!(slots.free.len(), slots.next, "kernel stacks still in use");
+    }
+}
+
+pub struct Stack {
+    slot: usize,
+    //STACK_PAGES contiguous pages, through the direct map
+    frames: *mut u8,
+}
+
+//the stack is only reachable through whoever owns it
+unsafe impl Send for Stack {}
+
+impl Stack {
+    pub fn new() -> Result<Stack, &'static str> {
+        let frames: *mut u8 = memory_alloc::allocate_pages(STACK_PAGES)?;
+        let slot: Result<usize, &'static str> = trap::without_interrupts(|| {
+            let mut slots = SLOTS.lock();
+            let slots: &mut Slots = slots.as_mut().ok_or("kernel stacks not initialized")?;
+            let slot: usize = match slots.free.pop() {
+                Some(slot) => slot,
+                None if KERNEL_STACKS_START + (slots.next + 1) * SLOT_SIZE <= KERNEL_STACKS_END => {
+                    slots.next += 1;
+                    slots.next - 1
+                }
+                None => return Err("out of kernel stack slots"),
+            };
+            let bottom: usize = slot_bottom(slot);
+            let pa: usize = mmu::virt_to_phys(frames as usize);
+            let bits: usize =
+                page_table::accessed_dirty(PteBits::Read.val() | PteBits::Write.val())
+                    | PteBits::Globe.val();
+            for page in 0..STACK_PAGES {
+                let offset: usize = page * PAGE_SIZE;
+                let root: &mut PageTable = unsafe { &mut *slots.root };
+                if let Err(err) =
+                    page_table::map_page(bottom + offset, pa + offset, root, bits, PageSize::Kilo)
+                {
+                    //the pages below are ours and mapped, so this can't fail
+                    if offset > 0 {
+                        page_table::unmap_range(root, bottom, offset, slots.asid)
+                            .expect("failed to unmap a partly mapped kernel stack");
+                    }
+                    slots.free.push(slot);
+                    return Err(err);
+                }
+                //the hart may have cached the page as not mapped
+                mmu::flush_tlb(Some(bottom + offset), None);
+            }
+            Ok(slot)
+        });
+        match slot {
+            Ok(slot) => Ok(Stack { slot, frames }),
+            Err(err) => {
+                memory_alloc::deallocate_pages(frames);
+                Err(err)
+            }
+        }
+    }
+
+    //lowest usable address, the guard page is right below
+    pub fn bottom(&self) -> usize {
+        slot_bottom(self.slot)
+    }
+
+    //where sp starts
+    pub fn top(&self) -> usize {
+        self.bottom() + STACK_PAGES * PAGE_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        trap::without_interrupts(|| {
+            let mut slots = SLOTS.lock();
+            let slots: &mut Slots = slots.as_mut().expect("kernel stacks not initialized");
+            let root: &mut PageTable = unsafe { &mut *slots.root };
+            //the pages are global, unmap_range flushes them out of every asid
+            page_table::unmap_range(root, self.bottom(), STACK_PAGES * PAGE_SIZE, slots.asid)
+                .expect("failed to unmap kernel stack");
+            slots.free.push(self.slot);
+        });
+        memory_alloc::deallocate_pages(self.frames);
+    }
+}
+
+fn slot_bottom(slot: usize) -> usize {
+    KERNEL_STACKS_START + slot * SLOT_SIZE + PAGE_SIZE
+}
@@ -0,0 +1,168 @@
+// System calls
+// user code does an ecall with the syscall number in a7 and up to six arguments in a0-a5,
+// the result comes back in a0, where a value between -4095 and -1 is a negated Errno
+// the numbers are indexes into TABLE, they are our own and not linux's (the errnos are)
+// pointers from user code aren't trusted, they go through copy_from_user/copy_to_user,
+// which check every page against the caller's page table first
+
+use crate::clint;
+use crate::print;
+use crate::process::Process;
+use crate::uart;
+use alloc::vec::Vec;
+
+const STDIN: usize = 0;
+const STDOUT: usize = 1;
+const STDERR: usize = 2;
+
+//clock_gettime clocks, there is no realtime clock to offer
+const CLOCK_MONOTONIC: usize = 1;
+
+//bytes copied through the kernel at a time by write
+const WRITE_CHUNK: usize = 256;
+
+#[repr(isize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Errno {
+    BadFd = 9,
+    NoMemory = 12,
+    Fault = 14,
+    Invalid = 22,
+    NoSys = 38,
+}
+
+//what happens to the process after a syscall
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    //back to the program with this in a0
+    Return(usize),
+    //back to whoever ran the process, the program sees 0 when it runs again
+    Yield,
+    Exit(usize),
+}
+
+type Handler = fn(&mut Process, &[usize; 6]) -> Result<Outcome, Errno>;
+
+//indexed by syscall number, user_programs.S has the numbers too
+static TABLE: [Handler; 7] = [
+    sys_exit,          //0
+    sys_write,         //1
+    sys_read,          //2
+    sys_getpid,        //3
+    sys_yield,         //4
+    sys_sbrk,          //5
+    sys_clock_gettime, //6
+];
+
+//handle the ecall the process just made, the result is already in its a0
+pub fn dispatch(process: &mut Process) -> Outcome {
+    let regs: &[usize; 32] = &process.frame().regs;
+    let number: usize = regs[17];
+    let args: [usize; 6] = [regs[10], regs[11], regs[12], regs[13], regs[14], regs[15]];
+
+    let result: Result<Outcome, Errno> = match TABLE.get(number) {
+        Some(handler) => handler(process, &args),
+        None => Err(Errno::NoSys),
+    };
+    let outcome: Outcome = match result {
+        Ok(outcome) => outcome,
+        Err(errno) => Outcome::Return(-(errno as isize) as usize),
+    };
+    match outcome {
+        Outcome::Return(value) => process.frame_mut().regs[10] = value,
+        Outcome::Yield => process.frame_mut().regs[10] = 0,
+        Outcome::Exit(_) => {}
+    }
+    outcome
+}
+
+//exit(code)
+fn sys_exit(_process: &mut Process, args: &[usize; 6]) -> Result<Outcome, Errno> {
+    Ok(Outcome::Exit(args[0]))
+}
+
+//write(fd, buffer, len), only the console for now
+fn sys_write(process: &mut Process, args: &[usize; 6]) -> Result<Outcome, Errno> {
+    let (fd, buffer, len): (usize, usize, usize) = (args[0], args[1], args[2]);
+    if fd != STDOUT && fd != STDERR {
+        return Err(Errno::BadFd);
+    }
+    let mut chunk: [u8; WRITE_CHUNK] = [0; WRITE_CHUNK];
+    let mut written: usize = 0;
+    while written < len {
+        let size: usize = (len - written).min(WRITE_CHUNK);
+        let address: usize = buffer.checked_add(written).ok_or(Errno::Fault)?;
+        process
+            .space_mut()
+            .copy_from_user(address, &mut chunk[..size])
+            .map_err(|_| Errno::Fault)?;
+        //the console takes text, anything that isn't utf-8 shows up as a replacement character
+        for piece in chunk[..size].utf8_chunks() {
+            print!("{}", piece.valid());
+            if !piece.invalid().is_empty() {
+                print!("\u{fffd}");
+            }
+        }
+        written += size;
+    }
+    Ok(Outcome::Return(written))
+}
+
+//read(fd, buffer, len), waits for the first byte, then takes whatever else already arrived
+fn sys_read(process: &mut Process, args: &[usize; 6]) -> Result<Outcome, Errno> {
+    let (fd, buffer, len): (usize, usize, usize) = (args[0], args[1], args[2]);
+    if fd != STDIN {
+        return Err(Errno::BadFd);
+    }
+    if len == 0 {
+        return Ok(Outcome::Return(0));
+    }
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.push(uart::read_byte_wait());
+    while bytes.len() < len {
+        match uart::read_byte() {
+            Some(byte) => bytes.push(byte),
+            None => break,
+        }
+    }
+    process
+        .space_mut()
+        .copy_to_user(buffer, &bytes)
+        .map_err(|_| Errno::Fault)?;
+    Ok(Outcome::Return(bytes.len()))
+}
+
+//getpid()
+fn sys_getpid(process: &mut Process, _args: &[usize; 6]) -> Result<Outcome, Errno> {
+    Ok(Outcome::Return(process.pid()))
+}
+
+//yield()
+fn sys_yield(_process: &mut Process, _args: &[usize; 6]) -> Result<Outcome, Errno> {
+    Ok(Outcome::Yield)
+}
+
+//sbrk(increment), returns the old break
+fn sys_sbrk(process: &mut Process, args: &[usize; 6]) -> Result<Outcome, Errno> {
+    let old: usize = process
+        .sbrk(args[0] as isize)
+        .map_err(|_| Errno::NoMemory)?;
+    Ok(Outcome::Return(old))
+}
+
+//clock_gettime(clock, timespec), timespec is two u64s, seconds and nanoseconds
+fn sys_clock_gettime(process: &mut Process, args: &[usize; 6]) -> Result<Outcome, Errno> {
+    let (clock, timespec): (usize, usize) = (args[0], args[1]);
+    if clock != CLOCK_MONOTONIC {
+        return Err(Errno::Invalid);
+    }
+    let now: core::time::Duration = clint::Instant::now().since_boot();
+    let mut bytes: [u8; 16] = [0; 16];
+    bytes[..8].copy_from_slice(&now.as_secs().to_le_bytes());
+    bytes[8..].copy_from_slice(&(now.subsec_nanos() as u64).to_le_bytes());
+    process
+        .space_mut()
+        .copy_to_user(timespec, &bytes)
+        .map_err(|_| Errno::Fault)?;
+    Ok(Outcome::Return(0))
+}
@@ -0,0 +1,157 @@
+// Threads
+// a std::thread like front for the scheduler: spawn a closure, get a JoinHandle back and
+// join it for whatever the closure returned
+// a thread is a scheduler task, it runs on a guarded kernel stack (see stack.rs) and has a name
+// the result goes through a Packet both sides hold on to, the thread leaves it there when it
+// finishes and wakes whoever is waiting in join()
+
+use crate::clint;
+use crate::scheduler;
+use crate::trap;
+use alloc::sync::Arc;
+use core::time::Duration;
+
+struct Packet<T> {
+    result: Option<T>,
+    //id of the task blocked in join(), if there is one yet
+    waiter: Option<usize>,
+}
+
+pub struct JoinHandle<T> {
+    id: usize,
+    name: &'static str,
+    packet: Arc<spin::Mutex<Packet<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn is_finished(&self) -> bool {
+        trap::without_interrupts(|| self.packet.lock().result.is_some())
+    }
+
+    //wait for the thread to finish and take what it returned
+    pub fn join(self) -> T {
+        loop {
+            //interrupts stay off from the check until we are blocked, or the wakeup gets lost
+            trap::disable_interrupts();
+            let result: Option<T> = {
+                let mut packet = self.packet.lock();
+                let result: Option<T> = packet.result.take();
+                if result.is_none() {
+                    packet.waiter = Some(scheduler::current_id());
+                }
+                result
+            };
+            if let Some(result) = result {
+                trap::enable_interrupts();
+                return result;
+            }
+            scheduler::block();
+            trap::enable_interrupts();
+        }
+    }
+}
+
+//sets up a thread, for when the defaults won't do
+pub struct Builder {
+    name: &'static str,
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder { name: "thread" }
+    }
+
+    pub fn name(self, name: &'static str) -> Builder {
+        Builder { name }
+    }
+
+    pub fn spawn<F, T>(self, f: F) -> Result<JoinHandle<T>, &'static str>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let packet: Arc<spin::Mutex<Packet<T>>> = Arc::new(spin::Mutex::new(Packet {
+            result: None,
+            waiter: None,
+        }));
+        let theirs: Arc<spin::Mutex<Packet<T>>> = packet.clone();
+        let id: usize = scheduler::spawn(self.name, move || {
+            let result: T = f();
+            let waiter: Option<usize> = trap::without_interrupts(|| {
+                let mut packet = theirs.lock();
+                packet.result = Some(result);
+                packet.waiter.take()
+            });
+            if let Some(waiter) = waiter {
+                scheduler::wake(waiter);
+            }
+        })?;
+        Ok(JoinHandle {
+            id,
+            name: self.name,
+            packet,
+        })
+    }
+}
+
+//run f in a new thread, panics if it can't be made
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new().spawn(f).expect("failed to spawn thread")
+}
+
+//what is running right now
+#[derive(Clone, Copy, Debug)]
+pub struct Thread {
+    id: usize,
+    name: &'static str,
+}
+
+impl Thread {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+pub fn current() -> Thread {
+    Thread {
+        id: scheduler::current_id(),
+        name: scheduler::current_name(),
+    }
+}
+
+//let the other ready threads run first
+pub fn yield_now() {
+    scheduler::yield_now();
+}
+
+//give up the hart for at least duration
+//before the scheduler is up that means waiting for the timer with the hart idle
+pub fn sleep(duration: Duration) {
+    if scheduler::is_running() {
+        scheduler::sleep(duration);
+    } else {
+        clint::sleep(duration);
+    }
+}
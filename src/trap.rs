@@ -0,0 +1,339 @@
+// Supervisor trap handling
+// trap.S saves every register into a TrapFrame and calls trap_handler,
+// when trap_handler returns the registers are restored from the frame and we sret
+// so anything written to the frame (eg sepc) takes effect on return
+// traps from user mode save into the process's own TrapFrame instead and go to
+// user_trap_handler, which decides whether the user code carries on or enter_user returns
+
+use crate::clint;
+use crate::mmu::address_space::{self, Access};
+use crate::mmu::page_table;
+use crate::plic;
+use crate::println;
+use crate::scheduler;
+use core::arch::asm;
+
+extern "C" {
+    fn s_trap_vector();
+    //run user code with the registers in frame until a trap the kernel has to deal with
+    pub fn enter_user(frame: *mut TrapFrame);
+    //lowest address the current kernel stack may use, trap.S checks sp against it
+    static mut kernel_stack_limit: usize;
+    //the kernel stack enter_user was called on
+    static mut user_kernel_sp: usize;
+}
+
+//what trap.S knows about the kernel stack the current task runs on
+//every task has its own, the scheduler swaps them with the tasks
+#[derive(Clone, Copy, Debug)]
+pub struct KernelStack {
+    pub limit: usize,
+    pub user_trap_sp: usize,
+}
+
+pub fn kernel_stack() -> KernelStack {
+    unsafe {
+        KernelStack {
+            limit: kernel_stack_limit,
+            user_trap_sp: user_kernel_sp,
+        }
+    }
+}
+
+//only with interrupts off, a trap in between would see half of it
+pub fn set_kernel_stack(stack: KernelStack) {
+    unsafe {
+        kernel_stack_limit = stack.limit;
+        user_kernel_sp = stack.user_trap_sp;
+    }
+}
+
+//layout must match the offsets in trap.S
+#[repr(C)]
+#[derive(Default)]
+pub struct TrapFrame {
+    pub regs: [usize; 32],
+    pub sepc: usize,
+    pub sstatus: usize,
+    pub scause: usize,
+    pub stval: usize,
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<TrapFrame>(), 36 * 8);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    SupervisorSoftware,
+    MachineSoftware,
+    SupervisorTimer,
+    MachineTimer,
+    SupervisorExternal,
+    MachineExternal,
+    Unknown(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    InstructionMisaligned,
+    InstructionAccessFault,
+    IllegalInstruction,
+    Breakpoint,
+    LoadMisaligned,
+    LoadAccessFault,
+    StoreMisaligned,
+    StoreAccessFault,
+    UserEnvCall,
+    SupervisorEnvCall,
+    MachineEnvCall,
+    InstructionPageFault,
+    LoadPageFault,
+    StorePageFault,
+    Unknown(usize),
+}
+
+//decoded mcause/scause, both registers use the same encoding
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cause {
+    Interrupt(Interrupt),
+    Exception(Exception),
+}
+
+impl Cause {
+    pub fn from_bits(bits: usize) -> Cause {
+        let interrupt_bit: usize = 1 << (usize::BITS - 1);
+        let code: usize = bits & !interrupt_bit;
+
+        if bits & interrupt_bit != 0 {
+            Cause::Interrupt(match code {
+                1 => Interrupt::SupervisorSoftware,
+                3 => Interrupt::MachineSoftware,
+                5 => Interrupt::SupervisorTimer,
+                7 => Interrupt::MachineTimer,
+                9 => Interrupt::SupervisorExternal,
+                11 => Interrupt::MachineExternal,
+                _ => Interrupt::Unknown(code),
+            })
+        } else {
+            Cause::Exception(match code {
+                0 => Exception::InstructionMisaligned,
+                1 => Exception::InstructionAccessFault,
+                2 => Exception::IllegalInstruction,
+                3 => Exception::Breakpoint,
+                4 => Exception::LoadMisaligned,
+                5 => Exception::LoadAccessFault,
+                6 => Exception::StoreMisaligned,
+                7 => Exception::StoreAccessFault,
+                8 => Exception::UserEnvCall,
+                9 => Exception::SupervisorEnvCall,
+                11 => Exception::MachineEnvCall,
+                12 => Exception::InstructionPageFault,
+                13 => Exception::LoadPageFault,
+                15 => Exception::StorePageFault,
+                _ => Exception::Unknown(code),
+            })
+        }
+    }
+}
+
+impl Exception {
+    fn is_page_fault(&self) -> bool {
+        matches!(
+            self,
+            Exception::InstructionPageFault | Exception::LoadPageFault | Exception::StorePageFault
+        )
+    }
+
+    fn is_misaligned(&self) -> bool {
+        matches!(
+            self,
+            Exception::InstructionMisaligned
+                | Exception::LoadMisaligned
+                | Exception::StoreMisaligned
+        )
+    }
+}
+
+//sstatus.SPP, the privilege mode the trap came from (sret goes back to)
+const SSTATUS_SPP: usize = 1 << 8;
+//sstatus.SPIE, what sret sets SIE to
+const SSTATUS_SPIE: usize = 1 << 5;
+//sstatus.FS, the floating point registers aren't saved, so user code doesn't get them
+const SSTATUS_FS: usize = 0b11 << 13;
+
+impl TrapFrame {
+    //registers for user code starting at entry with the stack at sp, with interrupts on
+    pub fn new_user(entry: usize, sp: usize) -> TrapFrame {
+        let sstatus: usize;
+        unsafe { asm!("csrr {}, sstatus", out(reg) sstatus) }
+        let mut frame: TrapFrame = TrapFrame {
+            sepc: entry,
+            sstatus: (sstatus & !(SSTATUS_SPP | SSTATUS_SIE | SSTATUS_FS)) | SSTATUS_SPIE,
+            ..TrapFrame::default()
+        };
+        frame.regs[2] = sp;
+        frame
+    }
+
+    pub fn cause(&self) -> Cause {
+        Cause::from_bits(self.scause)
+    }
+
+    //true if the trap came from supervisor mode (SPP bit of sstatus)
+    pub fn is_from_supervisor(&self) -> bool {
+        self.sstatus & SSTATUS_SPP != 0
+    }
+
+    //move sepc past the instruction that trapped
+    //the kernel is built with compressed instructions so check the length first
+    pub fn skip_instruction(&mut self) {
+        let low_half: u16 = unsafe { (self.sepc as *const u16).read_volatile() };
+        if low_half & 0b11 == 0b11 {
+            self.sepc += 4;
+        } else {
+            self.sepc += 2;
+        }
+    }
+}
+
+pub fn init() {
+    let vector: usize = s_trap_vector as *const () as usize;
+    assert!(vector % 4 == 0);
+    //s_trap_vector takes a nonzero sscratch for a trap from user mode, and its reset value
+    //isn't specified
+    unsafe { asm!("csrw sscratch, zero") }
+    unsafe { asm!("csrw stvec, {}", in(reg) vector) }
+}
+
+//sstatus.SIE, global supervisor interrupt enable
+const SSTATUS_SIE: usize = 1 << 1;
+//sip.SSIP, the machine mode timer handler raises this to forward timer ticks
+const SIP_SSIP: usize = 1 << 1;
+
+pub fn enable_interrupts() {
+    unsafe { asm!("csrs sstatus, {}", in(reg) SSTATUS_SIE) }
+}
+
+pub fn disable_interrupts() {
+    unsafe { asm!("csrc sstatus, {}", in(reg) SSTATUS_SIE) }
+}
+
+pub fn interrupts_enabled() -> bool {
+    let sstatus: usize;
+    unsafe { asm!("csrr {}, sstatus", out(reg) sstatus) }
+    sstatus & SSTATUS_SIE != 0
+}
+
+//run f with interrupts off, then put them back how they were
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let were_enabled: bool = interrupts_enabled();
+    if were_enabled {
+        disable_interrupts();
+    }
+    let out: T = f();
+    if were_enabled {
+        enable_interrupts();
+    }
+    out
+}
+
+pub fn report_fault(frame: &TrapFrame, exception: Exception) {
+    println!("{:?}", exception);
+    println!("sepc  = {:#018x}", frame.sepc);
+    println!("stval = {:#018x}", frame.stval);
+    println!(
+        "from {} mode",
+        if frame.is_from_supervisor() {
+            "supervisor"
+        } else {
+            "user"
+        }
+    );
+}
+
+fn fatal_fault(frame: &TrapFrame, exception: Exception) -> ! {
+    report_fault(frame, exception);
+    //trap.S switched to the overflow stack, the guard page below the stack caught it
+    if frame.is_from_supervisor()
+        && frame.regs[2] < unsafe { kernel_stack_limit } + core::mem::size_of::<TrapFrame>()
+    {
+        panic!("kernel stack overflow, sp = {:#018x}", frame.regs[2]);
+    }
+    //returning would just run the same instruction again and fault forever
+    panic!("unhandled kernel fault");
+}
+
+fn page_fault_access(exception: Exception) -> Access {
+    match exception {
+        Exception::InstructionPageFault => Access::Execute,
+        Exception::LoadPageFault => Access::Read,
+        _ => Access::Write,
+    }
+}
+
+fn handle_exception(frame: &mut TrapFrame, exception: Exception) {
+    match exception {
+        Exception::Breakpoint => {
+            println!("breakpoint at {:#018x}", frame.sepc);
+            frame.skip_instruction();
+        }
+        _ if exception.is_page_fault() => {
+            let access: Access = page_fault_access(exception);
+            //a reserved page that hasn't been touched yet, returning runs the access again
+            if let Err(reason) = address_space::handle_page_fault(frame.stval, access) {
+                println!("page fault at {:#018x}: {}", frame.stval, reason);
+                fatal_fault(frame, exception);
+            }
+        }
+        _ if exception.is_misaligned() || exception == Exception::IllegalInstruction => {
+            fatal_fault(frame, exception);
+        }
+        _ => {
+            report_fault(frame, exception);
+            panic!("unhandled exception");
+        }
+    }
+}
+
+fn handle_interrupt(interrupt: Interrupt) {
+    match interrupt {
+        Interrupt::SupervisorSoftware => {
+            unsafe { asm!("csrc sip, {}", in(reg) SIP_SSIP) }
+            clint::handle_timer_interrupt();
+        }
+        Interrupt::SupervisorTimer => clint::handle_timer_interrupt(),
+        Interrupt::SupervisorExternal => plic::handle_interrupt(),
+        _ => println!("unhandled interrupt {:?}", interrupt),
+    }
+}
+
+//called from trap.S
+#[no_mangle]
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    match frame.cause() {
+        Cause::Exception(exception) => handle_exception(frame, exception),
+        Cause::Interrupt(interrupt) => {
+            handle_interrupt(interrupt);
+            scheduler::preempt();
+        }
+    }
+}
+
+//called from trap.S for traps from user mode, frame is the process's
+//true resumes the user code, false makes enter_user return so the kernel can look at the trap
+#[no_mangle]
+extern "C" fn user_trap_handler(frame: &mut TrapFrame) -> bool {
+    match frame.cause() {
+        Cause::Interrupt(interrupt) => {
+            handle_interrupt(interrupt);
+            scheduler::preempt();
+            true
+        }
+        //the upper half is the kernel's, a user fault there never gets a page
+        Cause::Exception(exception)
+            if exception.is_page_fault() && !page_table::is_upper_half(frame.stval) =>
+        {
+            address_space::handle_page_fault(frame.stval, page_fault_access(exception)).is_ok()
+        }
+        Cause::Exception(_) => false,
+    }
+}
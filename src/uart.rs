@@ -1,3 +1,11 @@
+use crate::scheduler;
+use crate::trap;
+use crate::WRITER;
+use alloc::vec::Vec;
+
+//uart0 interrupt source on the qemu virt plic
+pub const UART_IRQ: u32 = 10;
+
 pub struct UartWriter {
     uart_addr: usize,
 }
@@ -46,3 +54,86 @@ impl core::fmt::Write for UartWriter {
         Ok(())
     }
 }
+
+//bytes received by the interrupt handler that nobody has read yet
+struct InputBuffer {
+    bytes: [u8; 64],
+    head: usize,
+    len: usize,
+}
+
+impl InputBuffer {
+    const fn new() -> InputBuffer {
+        InputBuffer {
+            bytes: [0; 64],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == self.bytes.len() {
+            return false;
+        }
+        let tail: usize = (self.head + self.len) % self.bytes.len();
+        self.bytes[tail] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte: u8 = self.bytes[self.head];
+        self.head = (self.head + 1) % self.bytes.len();
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+static INPUT: spin::Mutex<InputBuffer> = spin::Mutex::new(InputBuffer::new());
+//the tasks blocked in read_byte_wait, all of them are woken when input arrives and whoever
+//doesn't get a byte blocks again
+static READERS: spin::Mutex<Vec<usize>> = spin::Mutex::new(Vec::new());
+
+//registered with the plic for UART_IRQ
+//reading the receive buffer is what clears the interrupt, so drain it completely
+pub fn handle_interrupt() {
+    let mut writer = WRITER.lock();
+    let mut input = INPUT.lock();
+    while let Some(byte) = writer.uart_read_byte() {
+        //when the buffer is full the byte is dropped, nobody is reading, but the fifo still
+        //has to be emptied or the interrupt fires again right away
+        let _ = input.push(byte);
+    }
+    let readers: Vec<usize> = core::mem::take(&mut *READERS.lock());
+    for reader in readers {
+        scheduler::wake(reader);
+    }
+}
+
+//a byte that already arrived, if there is one
+pub fn read_byte() -> Option<u8> {
+    trap::without_interrupts(|| INPUT.lock().pop())
+}
+
+//sleep until a byte arrives, other tasks get the hart in the meantime
+pub fn read_byte_wait() -> u8 {
+    loop {
+        //interrupts stay off between checking the buffer and the wfi (or blocking) so we can't
+        //miss the wakeup, wfi still returns when an interrupt is pending even if they are disabled
+        trap::disable_interrupts();
+        if let Some(byte) = INPUT.lock().pop() {
+            trap::enable_interrupts();
+            return byte;
+        }
+        if scheduler::is_running() {
+            READERS.lock().push(scheduler::current_id());
+            scheduler::block();
+        } else {
+            unsafe { core::arch::asm!("wfi") }
+        }
+        trap::enable_interrupts();
+    }
+}